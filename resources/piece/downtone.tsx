@@ -0,0 +1,40 @@
+<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.9" name="downtone" tilewidth="8" tileheight="8" tilecount="12" columns="0">
+ <grid orientation="orthogonal" width="1" height="1"/>
+ <tile id="0" x="0" y="120" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="1" x="0" y="40" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="2" x="0" y="58" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="3" x="0" y="102" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="4" x="0" y="138" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="5" x="0" y="156" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="6" x="0" y="174" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="7" x="0" y="192" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="8" x="0" y="210" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="9" x="0" y="228" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="10" x="0" y="246" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+ <tile id="11" x="0" y="264" width="8" height="8">
+  <image source="../atlas.png"/>
+ </tile>
+</tileset>
@@ -0,0 +1,256 @@
+//! Headless tooling for level pieces: validation, previews, conversion and stats
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use image::{GenericImageView, RgbaImage};
+use downtone::level::{self, LevelPiece, MarkerType, TILE_DIMS};
+use downtone::tiles::{self, TileId, TileRegistry};
+use downtone::tiled::{self, TiledTileTable};
+
+/// Tile file used when `--tiles` isn't given, if it exists
+const DEFAULT_TILES: &str = "resources/tiles.json";
+const DEFAULT_TABLE: &str = "resources/tiled.table";
+
+const USAGE: &str = "usage: downtone-piece [--tiles <tiles.json>] [--table <tiled table>] <command> ...
+
+The tile files default to resources/tiles.json and resources/tiled.table when they exist.
+
+commands:
+  check <files...>                    validate pieces, exiting with 1 if any are invalid
+  ascii <file>                        print a piece as ASCII art
+  stats <files...>                    print size, tile histogram and markers
+  convert <in> <out>                  convert between .dntp, .json/.tmj and .tmx
+  render <file> <out.png> [atlas]     render a piece with the texture atlas (default resources/atlas.png),
+                                      using the tilesets of the biome given by --biome <name>
+  tileset <out.tsx> [atlas]           write the Tiled tileset converted maps refer to, with the atlas
+                                      path relative to the tileset (default ../atlas.png)";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // The registry has to be in place before anything is parsed, including the Tiled table
+    let tiles_path = take_option(&mut args, "--tiles")
+        .or_else(|| if Path::new(DEFAULT_TILES).exists() { Some(String::from(DEFAULT_TILES)) } else { None });
+    if let Some(path) = tiles_path {
+        match fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|s| TileRegistry::from_json(&s).map_err(|e| e.to_string())) {
+            Ok(registry) => tiles::set_registry(registry),
+            Err(e) => fail(&format!("{}: {}", path, e))
+        }
+    }
+    let table_path = take_option(&mut args, "--table")
+        .or_else(|| if Path::new(DEFAULT_TABLE).exists() { Some(String::from(DEFAULT_TABLE)) } else { None });
+    let table = match table_path {
+        Some(path) => match fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|s| TiledTileTable::from_string(&s).map_err(|e| e.to_string())) {
+            Ok(t) => t,
+            Err(e) => fail(&format!("{}: {}", path, e))
+        },
+        None => TiledTileTable::default()
+    };
+    let biome = take_option(&mut args, "--biome");
+    if args.is_empty() { fail(USAGE); }
+
+    let command = args.remove(0);
+    let ok = match (command.as_str(), args.len()) {
+        ("check", n) if n > 0 => check(&args, &table),
+        ("ascii", 1) => ascii(&args[0], &table),
+        ("stats", n) if n > 0 => stats(&args, &table),
+        ("convert", 2) => convert(&args[0], &args[1], &table),
+        ("render", 2) => render(&args[0], &args[1], "resources/atlas.png", biome.as_deref(), &table),
+        ("render", 3) => render(&args[0], &args[1], &args[2], biome.as_deref(), &table),
+        ("tileset", 1) => tileset(&args[0], "../atlas.png", &table),
+        ("tileset", 2) => tileset(&args[0], &args[1], &table),
+        _ => fail(USAGE)
+    };
+    if !ok { process::exit(1); }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(2);
+}
+
+/// Removes `--name value` from the arguments, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|a| a == name)?;
+    if index + 1 >= args.len() { fail(USAGE); }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+fn extension(path: &str) -> &str {
+    Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("")
+}
+
+/// Reads a piece in any supported format, applying the same filename conventions as the game
+fn load(path: &str, table: &TiledTileTable) -> Result<LevelPiece, String> {
+    let string = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut piece = match extension(path) {
+        "tmx" => tiled::piece_from_tmx(&string, table).map_err(|e| format!("{}: {}", path, e))?,
+        "json" | "tmj" => tiled::piece_from_tiled_json(&string, table).map_err(|e| format!("{}: {}", path, e))?,
+        _ => level::piece_from_string(string).map_err(|e| format!("{} {}", path, e))?
+    };
+    level::apply_filename_conventions(path, &mut piece);
+    Ok(piece)
+}
+
+fn check(paths: &[String], table: &TiledTileTable) -> bool {
+    let mut all_ok = true;
+    for path in paths {
+        match load(path, table) {
+            Ok(piece) => {
+                let report = level::validate_piece(&piece, level::PLAYER_JUMP_HEIGHT);
+                if report.is_valid() {
+                    println!("{}: ok", path);
+                } else {
+                    all_ok = false;
+                    for problem in &report.problems {
+                        println!("{}: {}", path, problem);
+                    }
+                }
+            },
+            Err(e) => {
+                all_ok = false;
+                println!("{}", e);
+            }
+        }
+    }
+    all_ok
+}
+
+fn tile_char(tile: TileId) -> char {
+    tiles::registry().def(tile).glyph
+}
+
+fn marker_char(marker_type: MarkerType) -> char {
+    match marker_type {
+        MarkerType::PlayerSpawn => 'S',
+        MarkerType::Enemy => 'E',
+        MarkerType::Pickup => 'P',
+        MarkerType::Exit => 'X'
+    }
+}
+
+fn ascii(path: &str, table: &TiledTileTable) -> bool {
+    let piece = match load(path, table) {
+        Ok(p) => p,
+        Err(e) => { eprintln!("{}", e); return false; }
+    };
+    for (y, row) in piece.data.iter().enumerate() {
+        let line: String = row.iter().enumerate().map(|(x, &tile)| {
+            match piece.markers.iter().find(|m| m.x == x && m.y == y) {
+                Some(m) => marker_char(m.marker_type),
+                None => tile_char(tile)
+            }
+        }).collect();
+        println!("{}", line);
+    }
+    true
+}
+
+fn stats(paths: &[String], table: &TiledTileTable) -> bool {
+    let mut all_ok = true;
+    for path in paths {
+        let piece = match load(path, table) {
+            Ok(p) => p,
+            Err(e) => { eprintln!("{}", e); all_ok = false; continue; }
+        };
+        let min_width = piece.data.iter().map(|r| r.len()).min().unwrap_or(0);
+        let max_width = piece.data.iter().map(|r| r.len()).max().unwrap_or(0);
+        println!("{}", path);
+        if let Some(name) = &piece.meta.name {
+            println!("  name: {}", name);
+        }
+        if min_width == max_width {
+            println!("  size: {}x{}", max_width, piece.data.len());
+        } else {
+            println!("  size: {}-{}x{} (ragged)", min_width, max_width, piece.data.len());
+        }
+
+        let total: usize = piece.data.iter().map(|r| r.len()).sum();
+        let registry = tiles::registry();
+        let name_width = registry.defs().map(|t| t.name.chars().count()).max().unwrap_or(0);
+        for tile in registry.defs() {
+            let count = piece.data.iter().flat_map(|r| r.iter()).filter(|&&t| t == tile.id).count();
+            let percent = if total > 0 { count as f32 * 100.0 / total as f32 } else { 0.0 };
+            println!("  {:<width$} {:>5} {:>5.1}%", tile.name, count, percent, width = name_width);
+        }
+        for marker in &piece.markers {
+            println!("  marker {} at ({}, {})", marker.marker_type.name(), marker.x, marker.y);
+        }
+    }
+    all_ok
+}
+
+fn convert(input: &str, output: &str, table: &TiledTileTable) -> bool {
+    let piece = match load(input, table) {
+        Ok(p) => p,
+        Err(e) => { eprintln!("{}", e); return false; }
+    };
+    let string = match extension(output) {
+        "tmx" => tiled::piece_to_tmx(&piece, table),
+        "json" | "tmj" => tiled::piece_to_tiled_json(&piece, table),
+        "dntp" => match level::piece_to_string(&piece) {
+            Ok(s) => s,
+            Err(e) => { eprintln!("{}: {}", output, e); return false; }
+        },
+        other => { eprintln!("{}: unknown output format '{}'", output, other); return false; }
+    };
+    match fs::write(output, string) {
+        Ok(_) => true,
+        Err(e) => { eprintln!("{}: {}", output, e); false }
+    }
+}
+
+fn tileset(output: &str, atlas_source: &str, table: &TiledTileTable) -> bool {
+    match fs::write(output, tiled::tileset_tsx(table, atlas_source)) {
+        Ok(_) => true,
+        Err(e) => { eprintln!("{}: {}", output, e); false }
+    }
+}
+
+/// Renders a piece the way the game autotiles it, without the level colour or lighting
+fn render(input: &str, output: &str, atlas_path: &str, biome: Option<&str>, table: &TiledTileTable) -> bool {
+    let piece = match load(input, table) {
+        Ok(p) => p,
+        Err(e) => { eprintln!("{}", e); return false; }
+    };
+    let atlas = match image::open(atlas_path) {
+        Ok(a) => a,
+        Err(e) => { eprintln!("{}: {}", atlas_path, e); return false; }
+    };
+
+    let size = TILE_DIMS as u32;
+    let height = piece.data.len();
+    let width = piece.data.iter().map(|r| r.len()).max().unwrap_or(0);
+    let mut image = RgbaImage::new(width as u32 * size, height as u32 * size);
+    let registry = tiles::registry();
+    // Out of bounds neighbours count as the same type, as in `Level::init_textures`
+    let same = |x: isize, y: isize, tile: TileId| {
+        if y < 0 || y as usize >= height || x < 0 || x as usize >= width { return true; }
+        piece.data[y as usize].get(x as usize).is_none_or(|&t| t == tile)
+    };
+
+    for (y, row) in piece.data.iter().enumerate() {
+        for (x, &tile) in row.iter().enumerate() {
+            let (xi, yi) = (x as isize, y as isize);
+            let adjacent = [same(xi, yi - 1, tile), same(xi + 1, yi, tile), same(xi, yi + 1, tile), same(xi - 1, yi, tile)];
+            let rect = level::tile_texture_rect(registry.def(tile).region_for(biome), level::autotile_index(adjacent));
+            for py in 0..size {
+                for px in 0..size {
+                    let (sx, sy) = (rect.x as u32 + px, rect.y as u32 + py);
+                    if sx < atlas.width() && sy < atlas.height() {
+                        image.put_pixel(x as u32 * size + px, y as u32 * size + py, atlas.get_pixel(sx, sy));
+                    }
+                }
+            }
+        }
+    }
+    match image.save(output) {
+        Ok(_) => true,
+        Err(e) => { eprintln!("{}: {}", output, e); false }
+    }
+}
@@ -0,0 +1,159 @@
+use std::path;
+use std::fs;
+use std::io::Read;
+use ggez::{Context, GameResult, GameError};
+use ggez::graphics::Color;
+use ggez::filesystem;
+use downtone::level::{self, Level, LevelPiece, LEVEL_WIDTH, TILE_DIMS};
+use downtone::tiles::{self, TileId};
+
+pub const MIN_PIECE_HEIGHT: usize = 1;
+
+/// A .dntp piece open in the editor
+pub struct Editor {
+    pub piece: LevelPiece,
+    pub path: path::PathBuf,
+    pub brush: TileId,
+    /// Set when the piece has changes that haven't been saved
+    pub dirty: bool,
+    pub playtesting: bool
+}
+
+impl Editor {
+    /// Opens a piece for editing. Unlike `piece_from_dntp` this doesn't validate,
+    /// so broken pieces can be opened and fixed.
+    pub fn open<P: AsRef<path::Path>>(ctx: &mut Context, path: P) -> GameResult<Editor> {
+        let mut dntp = String::new();
+        let mut f = filesystem::open(ctx, &path)?;
+        f.read_to_string(&mut dntp)?;
+        let mut piece = level::piece_from_string(dntp).map_err(|e| {
+            GameError::ResourceLoadError(format!("{} {}", path.as_ref().display(), e))
+        })?;
+
+        let dirty = normalize_piece(&mut piece);
+        Ok(Editor {
+            piece: piece,
+            path: path.as_ref().to_path_buf(),
+            brush: tiles::registry().solid,
+            dirty: dirty,
+            playtesting: false
+        })
+    }
+
+    /// Lists the .dntp files in the piece directory, sorted by name
+    pub fn list_pieces(ctx: &mut Context) -> GameResult<Vec<path::PathBuf>> {
+        Ok(level::list_piece_files(ctx, level::PIECE_DIR)?.into_iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "dntp"))
+            .collect())
+    }
+
+    /// Opens the piece after (or before, if `step` is negative) the current one in the piece directory
+    pub fn open_next(&self, ctx: &mut Context, step: isize) -> GameResult<Editor> {
+        let paths = Editor::list_pieces(ctx)?;
+        if paths.is_empty() {
+            return Editor::open(ctx, &self.path);
+        }
+        let current = paths.iter().position(|p| p == &self.path).unwrap_or(0) as isize;
+        let next = (current + step).rem_euclid(paths.len() as isize) as usize;
+        Editor::open(ctx, &paths[next])
+    }
+
+    /// Builds a level containing just this piece, fully explored, for drawing and play-testing
+    pub fn build_level(&self, ctx: &mut Context, color: Color) -> Level {
+        let mut level = Level::new(color);
+        level.push_piece(ctx, &self.piece);
+        level.init_textures(ctx);
+        level.explore_all();
+        level
+    }
+
+    /// Selects the tile `step` places after (or before, if negative) the brush in registry order, wrapping around
+    pub fn cycle_brush(&mut self, step: isize) {
+        let ids = tiles::registry().ids();
+        if ids.is_empty() { return; }
+        let current = ids.iter().position(|&t| t == self.brush).unwrap_or(0) as isize;
+        self.brush = ids[(current + step).rem_euclid(ids.len() as isize) as usize];
+    }
+
+    /// Sets a tile of the piece, returning whether anything changed
+    pub fn paint(&mut self, x: usize, y: usize, tile: TileId) -> bool {
+        match self.piece.data.get_mut(y).and_then(|row| row.get_mut(x)) {
+            Some(t) if *t != tile => {
+                *t = tile;
+                self.dirty = true;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Adds a copy of the bottom row to the piece
+    pub fn add_row(&mut self) {
+        let row = self.piece.data.last().cloned().unwrap_or_else(|| vec![tiles::registry().empty; LEVEL_WIDTH as usize]);
+        self.piece.data.push(row);
+        self.dirty = true;
+    }
+
+    /// Removes the bottom row of the piece, along with any markers on it
+    pub fn remove_row(&mut self) {
+        if self.piece.data.len() <= MIN_PIECE_HEIGHT { return; }
+        self.piece.data.pop();
+        let height = self.piece.data.len();
+        self.piece.markers.retain(|m| m.y < height);
+        self.dirty = true;
+    }
+
+    /// Writes the piece back to its file in `resource_dir`, the resources directory on disk.
+    /// ggez only writes to the user data directory, which the resources directory would shadow.
+    pub fn save(&mut self, resource_dir: &path::Path) -> GameResult {
+        let relative = self.path.strip_prefix("/").unwrap_or(&self.path);
+        let disk_path = resource_dir.join(relative);
+        fs::write(&disk_path, level::piece_to_string(&self.piece)?)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// A one-line summary for the editor HUD
+    pub fn status(&self) -> String {
+        let report = level::validate_piece(&self.piece, level::PLAYER_JUMP_HEIGHT);
+        let problem = match report.problems.first() {
+            Some(p) => p.to_string(),
+            None => String::from("ok")
+        };
+        format!("{}{}  {}x{}  BRUSH {}  {}",
+            self.path.display(), if self.dirty { "*" } else { "" },
+            self.piece.data.first().map_or(0, |r| r.len()), self.piece.data.len(),
+            tiles::registry().def(self.brush).name.to_uppercase(), problem)
+    }
+}
+
+/// Pads or trims every row to `LEVEL_WIDTH` so the piece can be drawn as a grid.
+/// Returns whether the piece was changed.
+fn normalize_piece(piece: &mut LevelPiece) -> bool {
+    let width = LEVEL_WIDTH as usize;
+    let empty = tiles::registry().empty;
+    let mut changed = false;
+    if piece.data.is_empty() {
+        piece.data.push(vec![empty; width]);
+        changed = true;
+    }
+    for row in piece.data.iter_mut() {
+        if row.len() != width {
+            row.resize(width, empty);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Converts a mouse position to the tile under it in a piece `height` rows tall, matching how tiles are drawn
+pub fn mouse_to_tile(mouse_x: f32, mouse_y: f32, scroll_y: f32, screen_w: f32, height: usize) -> Option<(usize, usize)> {
+    let tile_size = TILE_DIMS * 6.0;
+    let x = mouse_x / tile_size - (screen_w / tile_size - LEVEL_WIDTH) / 2.0;
+    let y = (mouse_y + scroll_y) / tile_size;
+    if x < 0.0 || y < 0.0 || x >= LEVEL_WIDTH || y as usize >= height {
+        None
+    } else {
+        Some((x as usize, y as usize))
+    }
+}
@@ -1,38 +1,101 @@
 use std::path;
-use std::io::Read;
+use std::fmt;
+use std::collections::BinaryHeap;
+use std::error;
+use std::io::{Read, Write};
 use std::f32::{consts::PI};
-use ggez::{Context, GameResult};
-use ggez::graphics::{Rect, Color};
+use ggez::{Context, GameResult, GameError};
+use ggez::graphics::{self, Rect, Color};
 use ggez::filesystem;
 use ggez::nalgebra::Vector2;
-use crate::{CameraView, MainState, clamp};
+use fastrand;
+use crate::{CameraView, clamp};
+use crate::tiled;
+use crate::tiles::{self, TileId, Collision, Light};
 
 
 pub const LEVEL_WIDTH: f32 = 16.0;
 
 pub struct Level {
     pub tiles: Vec<Vec<LevelTile>>,
-    pub lightmap: Vec<Vec<u8>>,
+    /// Red, green and blue light on each tile
+    pub lightmap: Vec<Vec<[u8; 3]>>,
+    /// Tiles the player's light has reached, which stay dimly visible afterwards
+    pub explored: Vec<Vec<bool>>,
+    /// The player's position at the last `update_lightmap`, in level coordinates
+    pub light_pos: Vector2<f32>,
+    /// Lights not tied to a tile, in level coordinates
+    pub lights: Vec<LightSource>,
+    /// Rows of the lightmap that were up to date after the last `update_lightmap`
+    lit_rows: Option<(usize, usize)>,
+    /// Row ranges that need relighting because their tiles changed
+    light_dirty: Vec<(usize, usize)>,
+    /// The player and entity lights used by the last `update_lightmap`, to find the ones that moved
+    lit_sources: Vec<LightSource>,
+    pub markers: Vec<PieceMarker>,
     pub last_update: f32,
-    pub color: Color
+    pub color: Color,
+    /// Selects biome tileset overrides from the tile registry
+    pub biome: Option<String>,
+    pub stream: Option<FloorStream>,
+    /// Rows removed from the top by `drop_rows_above`, so `y + rows_dropped` is the depth into the floor
+    pub rows_dropped: usize
 }
 
 impl Level {
-    pub fn push_piece(&mut self, ctx: &mut Context, piece: &LevelPiece) {
+    pub fn new(color: Color) -> Level {
+        Level {
+            tiles: vec!(),
+            lightmap: vec!(),
+            explored: vec!(),
+            light_pos: Vector2::new(0.0, 0.0),
+            lights: vec!(),
+            lit_rows: None,
+            light_dirty: vec!(),
+            lit_sources: vec!(),
+            markers: vec!(),
+            last_update: 0.0,
+            color: color,
+            biome: None,
+            stream: None,
+            rows_dropped: 0
+        }
+    }
+
+    pub fn push_piece(&mut self, _ctx: &mut Context, piece: &LevelPiece) {
+        self.append_piece(piece);
+    }
+
+    /// Context-free version of `push_piece`, for code that runs without a window
+    pub fn append_piece(&mut self, piece: &LevelPiece) {
         assert!(piece.data.len() > 0);
+        let registry = tiles::registry();
         let vec_h = piece.data.len() as usize;
-        let vec_w = piece.get_width(ctx) as usize;
+        let vec_w = piece.data[0].len();
         let data = &piece.data;
+        let y_offset = self.height();
+        for marker in &piece.markers {
+            self.markers.push(PieceMarker { y: marker.y + y_offset, ..*marker });
+            if marker.marker_type == MarkerType::Exit {
+                self.lights.push(LightSource {
+                    pos: Vector2::new(marker.x as f32, (marker.y + y_offset) as f32),
+                    light: EXIT_LIGHT
+                });
+            }
+        }
         for i in 0..vec_h {
             let mut temp_vec: Vec<LevelTile> = vec!();
             for n in 0..vec_w {
-                let level_tile = type_to_tile(ctx, data[i][n]);
+                let level_tile = level_tile(&registry, data[i][n]);
                 temp_vec.push(level_tile);
             }
             let size = temp_vec.len();
             self.tiles.push(temp_vec);
-            self.lightmap.push(vec![15; size]);
+            self.lightmap.push(vec![[UNSEEN_LIGHT; 3]; size]);
+            self.explored.push(vec![false; size]);
         }
+        // Lights in the new rows can reach the rows above them
+        self.light_dirty.push((y_offset.saturating_sub(LIGHT_RADIUS), self.height() - 1));
     }
 
     pub fn get_tile(&self, _ctx: &mut Context, x: usize, y: usize) -> Option<LevelTile> {
@@ -43,7 +106,16 @@ impl Level {
         }
     }
 
-    pub fn comp_tile(&self, ctx: &mut Context, x: usize, y: usize, match_type: &TileType) -> bool {
+    /// Like `get_tile`, but anything outside the level is the solid tile, so the player can't leave it
+    pub fn get_tile_or_wall(&self, ctx: &mut Context, x: isize, y: isize) -> LevelTile {
+        let tile = if x < 0 || y < 0 { None } else { self.get_tile(ctx, x as usize, y as usize) };
+        tile.unwrap_or_else(|| {
+            let registry = tiles::registry();
+            type_to_tile(ctx, &registry, registry.solid)
+        })
+    }
+
+    pub fn comp_tile(&self, ctx: &mut Context, x: usize, y: usize, match_type: &TileId) -> bool {
         let tile = self.get_tile(ctx, x, y);
         match tile {
             Some(t) => { &(t.tile_type) == match_type },
@@ -51,6 +123,19 @@ impl Level {
         }
     }
 
+    /// Returns all markers of the given type, in level tile coordinates
+    pub fn markers_of(&self, marker_type: MarkerType) -> Vec<PieceMarker> {
+        self.markers.iter().filter(|m| m.marker_type == marker_type).cloned().collect()
+    }
+
+    pub fn find_marker(&self, marker_type: MarkerType) -> Option<PieceMarker> {
+        self.markers.iter().find(|m| m.marker_type == marker_type).cloned()
+    }
+
+    pub fn has_marker_at(&self, marker_type: MarkerType, x: usize, y: usize) -> bool {
+        self.markers.iter().any(|m| m.marker_type == marker_type && m.x == x && m.y == y)
+    }
+
     pub fn height(&self) -> usize {
         self.tiles.len()
     }
@@ -60,99 +145,306 @@ impl Level {
     }
 
     pub fn init_textures(&mut self, ctx: &mut Context) {
-        for i in 0..self.height() {
+        let height = self.height();
+        self.init_texture_rows(ctx, 0, height);
+    }
+
+    /// Picks textures for the rows in `from..to`
+    pub fn init_texture_rows(&mut self, ctx: &mut Context, from: usize, to: usize) {
+        for i in from..to.min(self.height()) {
             for n in 0..self.width() {
                 self.init_tile_texture(ctx, i, n);
             }
         }
     }
 
+    /// Removes every row above `y`, shifting markers to match.
+    /// Returns the number of rows removed, which callers must use to rebase anything in level or screen space.
+    pub fn drop_rows_above(&mut self, _ctx: &mut Context, y: usize) -> usize {
+        self.remove_rows_above(y)
+    }
+
+    /// Context-free version of `drop_rows_above`
+    pub fn remove_rows_above(&mut self, y: usize) -> usize {
+        let count = y.min(self.height());
+        if count == 0 { return 0; }
+
+        self.tiles.drain(0..count);
+        self.lightmap.drain(0..count);
+        self.explored.drain(0..count);
+        self.markers.retain(|m| m.y >= count);
+        for marker in self.markers.iter_mut() {
+            marker.y -= count;
+        }
+        self.lights.retain(|l| l.pos.y >= count as f32);
+        for light in self.lights.iter_mut().chain(self.lit_sources.iter_mut()) {
+            light.pos.y -= count as f32;
+        }
+        self.lit_rows = self.lit_rows.and_then(|rows| shift_rows(rows, count));
+        self.light_dirty = self.light_dirty.iter().filter_map(|&rows| shift_rows(rows, count)).collect();
+        // Those rows may have been lighting the new top rows
+        self.light_dirty.push((0, LIGHT_RADIUS));
+        self.rows_dropped += count;
+        self.light_pos.y -= count as f32;
+        count
+    }
+
+    /// Replaces a tile, retiling it and its neighbours and marking the area around it for relighting.
+    /// Returns false if the position is outside the level.
+    pub fn set_tile(&mut self, ctx: &mut Context, x: usize, y: usize, tile: TileId) -> bool {
+        if y >= self.height() || x >= self.tiles[y].len() { return false; }
+        self.tiles[y][x] = type_to_tile(ctx, &tiles::registry(), tile);
+
+        let (x_min, x_max) = (x.saturating_sub(1), (x + 1).min(self.width() - 1));
+        let (y_min, y_max) = (y.saturating_sub(1), (y + 1).min(self.height() - 1));
+        for n in y_min..=y_max {
+            for i in x_min..=x_max {
+                // Diagonal neighbours keep their texture, since autotiling only looks at the four sides
+                if i == x || n == y {
+                    self.init_tile_texture(ctx, n, i);
+                }
+            }
+        }
+        // Opening or closing a wall changes the light anywhere light from this tile could reach
+        self.light_dirty.push(light_rows(y as f32));
+        true
+    }
+
     fn init_tile_texture(&mut self, ctx: &mut Context, x: usize, y: usize) {
         let tile = self.tiles[x][y];
         let x_max = self.height() as usize-1;
         let y_max = self.width() as usize-1;
 
-        let atlas_region = TILE_REGIONS[tile.tile_type as usize];
-        assert!(atlas_region.w == 71.0 && atlas_region.h == 17.0, "Invalid atlas region for tile!");
+        let atlas_region = tiles::registry().def(tile.tile_type).region_for(self.biome.as_deref());
         let adjacent: [bool; 4] = [
             if x>0 { self.comp_tile(ctx, y, x-1, &tile.tile_type) } else { true },
             if y<y_max { self.comp_tile(ctx, y+1, x, &tile.tile_type) } else { true },
             if x<x_max { self.comp_tile(ctx, y, x+1, &tile.tile_type) } else { true },
             if y>0 { self.comp_tile(ctx, y-1, x, &tile.tile_type) } else { true },
         ];
-        let tex_id = match adjacent {
-            [true, true, true, true] => { 8 },
-            [true, true, true, false] => { 3 },
-            [true, true, false, true] => { 11 },
-            [true, false, true, true] => { 12 },
-            [false, true, true, true] => { 4 },
-            [true, true, false, false] => { 9 },
-            [true, false, true, false] => { 5 },
-            [false, true, true, false] => { 1 },
-            [true, false, false, true] => { 10 },
-            [false, true, false, true] => { 13 },
-            [false, false, true, true] => { 2 },
-            [true, false, false, false] => { 14 },
-            [false, true, false, false] => { 6 },
-            [false, false, true, false] => { 7 },
-            [false, false, false, true] => { 15 },
-            _ => 0
-        };
-        let tex = get_tile_texture_rect(ctx, atlas_region, tex_id);
+        let tex = get_tile_texture_rect(ctx, atlas_region, autotile_index(adjacent));
         self.tiles[x][y].tile_texture = Some(tex);
     }
 
     pub fn update_lightmap(&mut self, ctx: &mut Context, camera: &CameraView, screen_size: Vector2<f32>, player_pos: Vector2<f32>) {
-        let screen_y_tiles = screen_to_lvl_y(ctx, screen_size.y);
-        let y_min = clamp(screen_to_lvl_y(ctx, camera.scroll.y) as i8 - screen_y_tiles as i8, 0, self.height() as i8 - 1) as usize;
-        let y_max = clamp(y_min + 3 * screen_y_tiles as usize, y_min, self.height() - 1);
-        let player_pos = screen_to_lvl_coords(ctx, player_pos.x, player_pos.y, screen_size.x);
-
-        for i in 0..self.width() {
-            for n in y_min..=y_max {
-                self.update_light(ctx, i, n, player_pos);
+        if self.height() == 0 { return; }
+        let screen_y_tiles = screen_to_lvl_y(ctx, screen_size.y) as usize;
+        let y_min = (screen_to_lvl_y(ctx, camera.scroll.y).max(0.0) as usize).saturating_sub(screen_y_tiles).min(self.height() - 1);
+        let y_max = (y_min + 3 * screen_y_tiles).min(self.height() - 1);
+        self.light_pos = screen_to_lvl_coords(ctx, player_pos.x, player_pos.y, screen_size.x);
+
+        // Only rows that could look different are relit: those around changed tiles,
+        // around lights that moved, appeared or went out, and those that have just come into range
+        let sources = self.light_sources();
+        let mut dirty = std::mem::take(&mut self.light_dirty);
+        for i in 0..sources.len().max(self.lit_sources.len()) {
+            let (old, new) = (self.lit_sources.get(i), sources.get(i));
+            if old != new {
+                dirty.extend(old.into_iter().chain(new).map(|l| light_rows(l.pos.y)));
             }
         }
+        match self.lit_rows {
+            Some((lit_min, lit_max)) => {
+                if y_min < lit_min { dirty.push((y_min, lit_min - 1)); }
+                if y_max > lit_max { dirty.push((lit_max + 1, y_max)); }
+            },
+            None => dirty.push((y_min, y_max))
+        }
+
+        for (from, to) in merge_rows(dirty) {
+            let (from, to) = (from.max(y_min), to.min(y_max));
+            if from <= to {
+                self.update_light(ctx, from, to);
+            }
+        }
+        self.lit_rows = Some((y_min, y_max));
+        self.lit_sources = sources;
+    }
+
+    /// Makes the next `update_lightmap` relight every row in range, for changes it can't track,
+    /// such as tiles edited without `set_tile`
+    pub fn invalidate_lightmap(&mut self) {
+        self.lit_rows = None;
     }
 
-    fn update_light(&mut self, _ctx: &mut Context, x: usize, y: usize, player_pos: Vector2<f32>) {
+    /// Light at the top left corner of tile `(x, y)`, averaged over the tiles that share it.
+    /// `x` and `y` can be one past the last column or row, for the corners on the far edges.
+    pub fn corner_light(&self, x: usize, y: usize) -> [f32; 3] {
+        let mut sum = [0.0; 3];
+        let mut count = 0;
+        for ty in y.saturating_sub(1)..(y + 1).min(self.height()) {
+            for tx in x.saturating_sub(1)..(x + 1).min(self.width()) {
+                for (total, &channel) in sum.iter_mut().zip(self.lightmap[ty][tx].iter()) {
+                    *total += channel as f32;
+                }
+                count += 1;
+            }
+        }
+        if count == 0 { return [AMBIENT_LIGHT as f32; 3]; }
+        [sum[0] / count as f32, sum[1] / count as f32, sum[2] / count as f32]
+    }
 
-        let mut light = 60i8;
+    /// Marks every tile as explored, so the whole level is visible without being visited
+    pub fn explore_all(&mut self) {
+        for row in self.explored.iter_mut() {
+            for tile in row.iter_mut() {
+                *tile = true;
+            }
+        }
+        self.invalidate_lightmap();
+    }
+
+    /// The player's light followed by `lights`
+    fn light_sources(&self) -> Vec<LightSource> {
+        let mut sources = vec!(LightSource { pos: self.light_pos, light: PLAYER_LIGHT });
+        sources.extend(self.lights.iter().cloned());
+        sources
+    }
 
-        //let target_x = player_pos.x as usize;
-        //let target_y = player_pos.y as usize;
-        
-        let dist = ((player_pos.y - y as f32).powf(2.0) + (player_pos.x - x as f32).powf(2.0)).sqrt() * 8.0;
-        light = clamp(light - dist as i8, 12, 60);
-        self.lightmap[y][x] = light as u8;
+    /// Spreads light from the player, `lights` and glowing tiles over the rows in `y_min..=y_max`.
+    /// Each light fades out over its radius and stops at solid tiles, which are lit but cast shadows.
+    /// The colours of overlapping lights add up, and tiles the player's light reaches are marked explored.
+    /// Sources up to `LIGHT_RADIUS` rows outside the range are included so its edges are lit correctly.
+    fn update_light(&mut self, _ctx: &mut Context, y_min: usize, y_max: usize) {
+        let width = self.width();
+        let top = y_min.saturating_sub(LIGHT_RADIUS);
+        let bottom = (y_max + LIGHT_RADIUS).min(self.height() - 1);
 
-        // 👇👇👇 too hard :( 
-        /*let mut cur_x = x as f32;
-        let mut cur_y = y as f32;
-        while (cur_x != target_x || cur_y != target_y) && light > 3 {
-            //let dist = ((target_y - cur_y as usize).pow(2) as f32 + (target_x - cur_x).pow(2) as f32).sqrt();
-            let angle = fit_angle(cur_y.atan2(cur_x) - target_y.atan2(target_x));
-            println!("Tracing tile: ({}, {}) | Angle: {}", cur_x, cur_y, angle / (2.0 * PI) * 360.0);
-            match angle {
-                a if a >= PI/4.0 && a <= PI*3.0/4.0 => { cur_y -= 1.0; },
-                a if a >= PI*3.0/4.0 && a <= PI*5.0/4.0 => { cur_x -= 1.0; },
-                a if a >= PI*5.0/4.0 && a <= PI*7.0/4.0 => { cur_y += 1.0; },
-                _ => { cur_x += 1.0 }
+        let mut sources = self.light_sources();
+        for y in top..=bottom {
+            for x in 0..width {
+                if let Some(light) = self.tiles[y][x].light {
+                    sources.push(LightSource { pos: Vector2::new(x as f32, y as f32), light: light });
+                }
             }
-            light -= 1;
-            if self.get_tile(ctx, cur_x as usize, cur_y as usize).unwrap().collide {
-                light = 0;
+        }
+
+        let mut light = vec![vec![[0.0f32; 3]; width]; bottom - top + 1];
+        // What's left of the current source's radius on each tile, in hundredths of a tile
+        let mut reach = vec![vec![0u32; width]; bottom - top + 1];
+        let mut reached: Vec<(usize, usize)> = vec!();
+        for (index, source) in sources.into_iter().enumerate() {
+            let (px, py) = (source.pos.x, source.pos.y);
+            if px < 0.0 || py < 0.0 || (px as usize) >= width || (py as usize) < top || (py as usize) > bottom { continue; }
+            let radius = (source.light.radius * 100.0) as u32;
+            // Max-heap of (reach, x, y), so each tile is settled by its shortest path first
+            let mut queue: BinaryHeap<(u32, usize, usize)> = BinaryHeap::new();
+            // Tiles around the source are seeded by distance, so moving lights shift smoothly between tiles
+            let (tx, ty) = (px as usize, py as usize);
+            for y in ty.saturating_sub(1).max(top)..=(ty + 1).min(bottom) {
+                for x in tx.saturating_sub(1)..=(tx + 1).min(width - 1) {
+                    let dist = ((py - y as f32).powf(2.0) + (px - x as f32).powf(2.0)).sqrt() * 100.0;
+                    queue.push((radius.saturating_sub(dist as u32), x, y));
+                }
+            }
+
+            while let Some((value, x, y)) = queue.pop() {
+                if value <= reach[y - top][x] { continue; }
+                if reach[y - top][x] == 0 { reached.push((x, y)); }
+                reach[y - top][x] = value;
+                if self.tiles[y][x].collision == Collision::Solid { continue; }
+
+                for dy in -1isize..=1 {
+                    for dx in -1isize..=1 {
+                        let (nx, ny) = (x as isize + dx, y as isize + dy);
+                        if (dx == 0 && dy == 0) || nx < 0 || nx >= width as isize || ny < top as isize || ny > bottom as isize { continue; }
+                        let (nx, ny) = (nx as usize, ny as usize);
+                        let step = if dx != 0 && dy != 0 {
+                            // Light can't squeeze diagonally between two solid tiles
+                            if self.tiles[y][nx].collision == Collision::Solid && self.tiles[ny][x].collision == Collision::Solid { continue; }
+                            141
+                        } else {
+                            100
+                        };
+                        let next = value.saturating_sub(step);
+                        if next > reach[ny - top][nx] {
+                            queue.push((next, nx, ny));
+                        }
+                    }
+                }
+            }
+
+            let color = source.light.color;
+            for (x, y) in reached.drain(..) {
+                let brightness = MAX_LIGHT as f32 * reach[y - top][x] as f32 / radius as f32;
+                let tile = &mut light[y - top][x];
+                tile[0] += brightness * color.r;
+                tile[1] += brightness * color.g;
+                tile[2] += brightness * color.b;
+                reach[y - top][x] = 0;
+                // The player's light always comes first
+                if index == 0 {
+                    self.explored[y][x] = true;
+                }
+            }
+        }
+
+        let rows = y_min..=y_max.min(bottom);
+        let lit_rows = self.lightmap[rows.clone()].iter_mut().zip(&self.explored[rows]).zip(&light[y_min - top..]);
+        for ((lit_row, explored_row), light_row) in lit_rows {
+            for ((lit, &explored), tile) in lit_row.iter_mut().zip(explored_row).zip(light_row) {
+                let floor = if explored { AMBIENT_LIGHT } else { UNSEEN_LIGHT };
+                let channel = |c: f32| clamp(c, floor as f32, MAX_LIGHT as f32) as u8;
+                *lit = [channel(tile[0]), channel(tile[1]), channel(tile[2])];
             }
         }
-        self.lightmap[y][x] = light;*/
     }
 }
 
+/// A light not tied to a tile, such as an exit's glow
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LightSource {
+    /// Position in level tile coordinates
+    pub pos: Vector2<f32>,
+    pub light: Light
+}
+
+/// Light on the lightmap's 0-60 scale
+pub const MAX_LIGHT: u8 = 60;
+/// Explored tiles never go darker than this, so places the player has been stay visible
+pub const AMBIENT_LIGHT: u8 = 12;
+/// The darkest tiles the player hasn't explored yet can get
+pub const UNSEEN_LIGHT: u8 = 4;
+/// How many rows away a light can affect, allowing for sources between tiles
+pub const LIGHT_RADIUS: usize = tiles::MAX_LIGHT_RADIUS as usize + 1;
+pub const PLAYER_LIGHT: Light = Light { color: graphics::WHITE, radius: 7.5 };
+pub const EXIT_LIGHT: Light = Light { color: Color { r: 0.5, g: 1.0, b: 0.6, a: 1.0 }, radius: 4.0 };
+
+/// The rows a light at row `y` can reach
+fn light_rows(y: f32) -> (usize, usize) {
+    let row = y.max(0.0) as usize;
+    (row.saturating_sub(LIGHT_RADIUS), row + LIGHT_RADIUS)
+}
+
+/// Moves a row range up by `count` rows, or returns `None` if it's entirely above them
+fn shift_rows((from, to): (usize, usize), count: usize) -> Option<(usize, usize)> {
+    if to < count { None } else { Some((from.saturating_sub(count), to - count)) }
+}
+
+/// Sorts row ranges and joins any that overlap or touch
+fn merge_rows(mut rows: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    rows.sort();
+    let mut merged: Vec<(usize, usize)> = vec!();
+    for (from, to) in rows {
+        match merged.last_mut() {
+            Some(last) if from <= last.1 + 1 => last.1 = last.1.max(to),
+            _ => merged.push((from, to))
+        }
+    }
+    merged
+}
+
 pub fn screen_to_lvl_coords(ctx: &mut Context, x: f32, y: f32, screen_w: f32) -> Vector2<f32> {
     let x_offset = 6.0 * (screen_w / 6.0 / TILE_DIMS - LEVEL_WIDTH) / 2.0;
     Vector2::new((x + x_offset) / TILE_DIMS / 6.0, screen_to_lvl_y(ctx, y))
 }
 
+/// Inverse of `screen_to_lvl_coords`
+pub fn lvl_to_screen_coords(_ctx: &mut Context, x: f32, y: f32, screen_w: f32) -> Vector2<f32> {
+    let x_offset = 6.0 * (screen_w / 6.0 / TILE_DIMS - LEVEL_WIDTH) / 2.0;
+    Vector2::new(x * TILE_DIMS * 6.0 - x_offset, y * TILE_DIMS * 6.0)
+}
+
 fn screen_to_lvl_y(_ctx: &mut Context, y: f32) -> f32 {
     y / TILE_DIMS / 6.0
 }
@@ -168,7 +460,76 @@ fn fit_angle(theta: f32) -> f32 {
 
 #[derive(Clone, Debug)]
 pub struct LevelPiece {
-    pub data: Vec<Vec<TileType>>
+    pub data: Vec<Vec<TileId>>,
+    pub meta: PieceMeta,
+    pub markers: Vec<PieceMarker>
+}
+
+/// A point of interest inside a piece, in tile coordinates relative to the piece
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PieceMarker {
+    pub marker_type: MarkerType,
+    pub x: usize,
+    pub y: usize
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MarkerType {
+    PlayerSpawn,
+    Enemy,
+    Pickup,
+    Exit
+}
+
+impl MarkerType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MarkerType::PlayerSpawn => "spawn",
+            MarkerType::Enemy => "enemy",
+            MarkerType::Pickup => "pickup",
+            MarkerType::Exit => "exit"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MarkerType> {
+        match name {
+            "spawn" => Some(MarkerType::PlayerSpawn),
+            "enemy" => Some(MarkerType::Enemy),
+            "pickup" => Some(MarkerType::Pickup),
+            "exit" => Some(MarkerType::Exit),
+            _ => None
+        }
+    }
+}
+
+/// Optional metadata from a v2 .dntp header
+#[derive(Clone, Debug, PartialEq)]
+pub struct PieceMeta {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub difficulty: u32,
+    pub biomes: Vec<String>,
+    pub weight: f32,
+    pub spawn: bool,
+    /// Whether the generator may use a horizontally mirrored copy of this piece
+    pub mirror: bool,
+    /// The generator may stack this piece on itself up to this many times
+    pub repeat: usize
+}
+
+impl Default for PieceMeta {
+    fn default() -> Self {
+        PieceMeta {
+            name: None,
+            author: None,
+            difficulty: 0,
+            biomes: vec!(),
+            weight: 1.0,
+            spawn: false,
+            mirror: true,
+            repeat: 1
+        }
+    }
 }
 
 impl LevelPiece {
@@ -176,69 +537,803 @@ impl LevelPiece {
         if self.data.len() == 0 { 0.0 }
         else { self.data[0].len() as f32 }
     }
-}
 
-pub fn piece_from_string(string: String) -> GameResult<LevelPiece> {
-    let mut rows = string.split('~');
-
-    let mut data: Vec<Vec<TileType>> = vec!();
-    let mut row_index: usize = 0;
-
-    loop {
-        let s = rows.next();
-        match s {
-            Some(row) => {
-                data.push(vec!());
-                let mut blocks = row.trim().split('_');
-                loop {
-                    let b = blocks.next();
-                    match b  {
-                        Some(block) => {
-                            let mut comp = block.split(':');
-
-                            let tile = comp.next().unwrap().parse::<usize>().unwrap();
-                            let count = comp.next().unwrap().parse::<usize>().unwrap();
-                            for _ in 0..count {
-                                data[row_index].push(TILES[tile].tile_type);
-                            }
-                        },
-                        _ => { break; },
+    /// A piece with an exit marker ends a floor
+    pub fn is_exit(&self) -> bool {
+        self.markers.iter().any(|m| m.marker_type == MarkerType::Exit)
+    }
+
+    /// Whether the player can fall from the bottom row of this piece into the top row of `next`
+    pub fn lines_up_with(&self, next: &LevelPiece) -> bool {
+        let registry = tiles::registry();
+        match (self.data.last(), next.data.first()) {
+            (Some(bottom), Some(top)) => bottom.iter().zip(top.iter())
+                .any(|(a, b)| !registry.collides(*a) && !registry.collides(*b)),
+            _ => false
+        }
+    }
+
+    /// Returns a copy of this piece flipped left to right, markers included
+    pub fn mirrored(&self) -> LevelPiece {
+        let width = self.data.first().map_or(0, |row| row.len());
+        LevelPiece {
+            data: self.data.iter().map(|row| row.iter().rev().cloned().collect()).collect(),
+            meta: self.meta.clone(),
+            markers: self.markers.iter()
+                .map(|m| PieceMarker { x: width - 1 - m.x.min(width - 1), ..*m })
+                .collect()
+        }
+    }
+
+    /// Returns this piece stacked on top of itself `times` times,
+    /// or `None` if the player couldn't fall from one copy into the next
+    pub fn repeated(&self, times: usize) -> Option<LevelPiece> {
+        if times == 0 || (times > 1 && !self.lines_up_with(self)) { return None; }
+
+        let height = self.data.len();
+        let mut piece = LevelPiece {
+            data: vec!(),
+            meta: self.meta.clone(),
+            markers: vec!()
+        };
+        for i in 0..times {
+            piece.data.extend(self.data.iter().cloned());
+            piece.markers.extend(self.markers.iter().map(|m| PieceMarker { y: m.y + i * height, ..*m }));
+        }
+        Some(piece)
+    }
+
+    /// Derives the transformed variants allowed by this piece's metadata, including the piece itself.
+    /// The piece's weight is split between its variants so adding them doesn't make it more common.
+    pub fn variants(&self) -> Vec<LevelPiece> {
+        let mut variants: Vec<LevelPiece> = vec!(self.clone());
+        if self.meta.mirror {
+            let mirrored = self.mirrored();
+            if mirrored.data != self.data {
+                variants.push(mirrored);
+            }
+        }
+        // Stacking would duplicate spawn and exit markers
+        if !self.meta.spawn && !self.is_exit() {
+            let bases = variants.clone();
+            for times in 2..=self.meta.repeat {
+                for base in &bases {
+                    if let Some(stacked) = base.repeated(times) {
+                        variants.push(stacked);
                     }
-                };
-                row_index += 1;
-            },
-            _ => { break; },
+                }
+            }
+        }
+
+        let weight = self.meta.weight / variants.len() as f32;
+        for variant in variants.iter_mut() {
+            variant.meta.weight = weight;
+            // Variants are final, deriving from them again would only produce duplicates
+            variant.meta.mirror = false;
+            variant.meta.repeat = 1;
+        }
+        variants
+    }
+}
+
+/// The newest .dntp version this build can read.
+/// v1 files have no header; v2 files start with `#dntp 2` and a `key: value` header ended by `---`.
+pub const DNTP_VERSION: u32 = 2;
+const DNTP_MAGIC: &str = "#dntp";
+const DNTP_HEADER_END: &str = "---";
+
+/// An error produced while parsing a .dntp piece.
+/// For header errors `row` is the header line and `block` is unused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PieceParseError {
+    pub row: usize,
+    pub block: usize,
+    pub text: String,
+    pub kind: PieceParseErrorKind
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PieceParseErrorKind {
+    MissingCount,
+    BadTileId,
+    BadCount,
+    UnknownTile,
+    BadVersion,
+    BadHeaderLine,
+    UnknownHeaderKey,
+    BadHeaderValue,
+    MissingHeaderEnd,
+    EmptyRow
+}
+
+impl fmt::Display for PieceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            PieceParseErrorKind::BadVersion
+            | PieceParseErrorKind::BadHeaderLine
+            | PieceParseErrorKind::UnknownHeaderKey
+            | PieceParseErrorKind::BadHeaderValue
+            | PieceParseErrorKind::MissingHeaderEnd => write!(f, "header line {}: ", self.row)?,
+            _ => write!(f, "row {}, block {}: ", self.row, self.block)?
+        };
+        match self.kind {
+            PieceParseErrorKind::MissingCount => write!(f, "block '{}' is missing a ':count'", self.text),
+            PieceParseErrorKind::BadTileId => write!(f, "tile id '{}' is not a number", self.text),
+            PieceParseErrorKind::BadCount => write!(f, "count '{}' is not a number", self.text),
+            PieceParseErrorKind::UnknownTile => write!(f, "tile id '{}' does not exist", self.text),
+            PieceParseErrorKind::BadVersion => write!(f, "unsupported version '{}'", self.text),
+            PieceParseErrorKind::BadHeaderLine => write!(f, "'{}' is not a 'key: value' pair", self.text),
+            PieceParseErrorKind::UnknownHeaderKey => write!(f, "unknown key '{}'", self.text),
+            PieceParseErrorKind::BadHeaderValue => write!(f, "invalid value '{}'", self.text),
+            PieceParseErrorKind::MissingHeaderEnd => write!(f, "header is not closed with '{}'", DNTP_HEADER_END),
+            PieceParseErrorKind::EmptyRow => write!(f, "row has no tiles")
         }
+    }
+}
+
+impl error::Error for PieceParseError {}
+
+impl From<PieceParseError> for GameError {
+    fn from(e: PieceParseError) -> GameError {
+        GameError::ResourceLoadError(e.to_string())
+    }
+}
+
+pub fn piece_from_string(string: String) -> Result<LevelPiece, PieceParseError> {
+    let trimmed = string.trim_start();
+    if trimmed.starts_with(DNTP_MAGIC) {
+        let (meta, markers, rows) = parse_dntp_header(trimmed)?;
+        Ok(LevelPiece {
+            data: parse_dntp_rows(rows)?,
+            meta: meta,
+            markers: markers
+        })
+    } else {
+        Ok(LevelPiece {
+            data: parse_dntp_rows(&string)?,
+            meta: PieceMeta::default(),
+            markers: vec!()
+        })
+    }
+}
+
+/// Parses a v2 header, returning the metadata, markers and the remaining tile rows.
+/// Markers are written as `marker: <type> <x> <y>` and may be repeated.
+fn parse_dntp_header(string: &str) -> Result<(PieceMeta, Vec<PieceMarker>, &str), PieceParseError> {
+    let mut meta = PieceMeta::default();
+    let mut markers: Vec<PieceMarker> = vec!();
+    let err = |kind: PieceParseErrorKind, line: usize, text: &str| PieceParseError {
+        row: line,
+        block: 0,
+        text: String::from(text),
+        kind: kind
     };
-    Ok(LevelPiece {
-        data: data
-    })
+
+    let mut offset = 0;
+    for (line_index, line) in string.split('\n').enumerate() {
+        offset += line.len() + 1;
+        let line = line.trim();
+
+        if line_index == 0 {
+            let version = line[DNTP_MAGIC.len()..].trim();
+            match version.parse::<u32>() {
+                Ok(v) if v >= 2 && v <= DNTP_VERSION => {},
+                _ => { return Err(err(PieceParseErrorKind::BadVersion, line_index, version)); }
+            }
+            continue;
+        }
+        if line == DNTP_HEADER_END {
+            return Ok((meta, markers, string.get(offset..).unwrap_or("")));
+        }
+        if line.is_empty() { continue; }
+
+        let mut pair = line.splitn(2, ':');
+        let key = pair.next().unwrap_or("").trim();
+        let value = match pair.next() {
+            Some(v) => v.trim(),
+            None => { return Err(err(PieceParseErrorKind::BadHeaderLine, line_index, line)); }
+        };
+        let bad_value = || err(PieceParseErrorKind::BadHeaderValue, line_index, value);
+        if key == "marker" {
+            let parts: Vec<&str> = value.split_whitespace().collect();
+            if parts.len() != 3 { return Err(bad_value()); }
+            markers.push(PieceMarker {
+                marker_type: MarkerType::from_name(parts[0]).ok_or_else(bad_value)?,
+                x: parts[1].parse::<usize>().map_err(|_| bad_value())?,
+                y: parts[2].parse::<usize>().map_err(|_| bad_value())?
+            });
+            continue;
+        }
+        set_meta_value(&mut meta, key, value).map_err(|kind| {
+            if kind == PieceParseErrorKind::UnknownHeaderKey {
+                err(kind, line_index, key)
+            } else {
+                err(kind, line_index, value)
+            }
+        })?;
+    }
+    Err(err(PieceParseErrorKind::MissingHeaderEnd, 0, ""))
+}
+
+/// The text form of every metadata field that differs from its default, as read by `set_meta_value`
+pub fn meta_values(meta: &PieceMeta) -> Vec<(&'static str, String)> {
+    let defaults = PieceMeta::default();
+    let mut values: Vec<(&'static str, String)> = vec!();
+    if let Some(name) = &meta.name { values.push(("name", name.clone())); }
+    if let Some(author) = &meta.author { values.push(("author", author.clone())); }
+    if meta.difficulty != defaults.difficulty { values.push(("difficulty", meta.difficulty.to_string())); }
+    if !meta.biomes.is_empty() { values.push(("biomes", meta.biomes.join(", "))); }
+    if meta.weight != defaults.weight { values.push(("weight", meta.weight.to_string())); }
+    if meta.spawn != defaults.spawn { values.push(("spawn", meta.spawn.to_string())); }
+    if meta.mirror != defaults.mirror { values.push(("mirror", meta.mirror.to_string())); }
+    if meta.repeat != defaults.repeat { values.push(("repeat", meta.repeat.to_string())); }
+    values
+}
+
+/// Sets one metadata field from its text form.
+/// Shared by .dntp headers and imported map properties, so text is trimmed as the header would be
+/// and line breaks, which can't be written back into a header, are rejected.
+pub fn set_meta_value(meta: &mut PieceMeta, key: &str, value: &str) -> Result<(), PieceParseErrorKind> {
+    let bad_value = || PieceParseErrorKind::BadHeaderValue;
+    if value.contains(&['\n', '\r'][..]) { return Err(bad_value()); }
+    let value = value.trim();
+    match key {
+        "name" => { meta.name = Some(String::from(value)); },
+        "author" => { meta.author = Some(String::from(value)); },
+        "difficulty" => { meta.difficulty = value.parse::<u32>().map_err(|_| bad_value())?; },
+        "biomes" => {
+            meta.biomes = value.split(',')
+                .map(|b| String::from(b.trim()))
+                .filter(|b| !b.is_empty())
+                .collect();
+        },
+        "weight" => {
+            meta.weight = match value.parse::<f32>() {
+                Ok(w) if w >= 0.0 && w.is_finite() => w,
+                _ => { return Err(bad_value()); }
+            };
+        },
+        "spawn" => { meta.spawn = value.parse::<bool>().map_err(|_| bad_value())?; },
+        "mirror" => { meta.mirror = value.parse::<bool>().map_err(|_| bad_value())?; },
+        "repeat" => {
+            meta.repeat = match value.parse::<usize>() {
+                Ok(n) if n >= 1 => n,
+                _ => { return Err(bad_value()); }
+            };
+        },
+        _ => { return Err(PieceParseErrorKind::UnknownHeaderKey); }
+    }
+    Ok(())
+}
+
+fn parse_dntp_rows(string: &str) -> Result<Vec<Vec<TileId>>, PieceParseError> {
+    let registry = tiles::registry();
+    let mut data: Vec<Vec<TileId>> = vec!();
+
+    let mut rows: Vec<&str> = string.split('~').map(|row| row.trim()).collect();
+    // Allow a trailing '~' (and blank lines) at the end of a file
+    while rows.last() == Some(&"") {
+        rows.pop();
+    }
+    for (row_index, row) in rows.into_iter().enumerate() {
+        // An empty row anywhere else would silently shift every row below it
+        if row.is_empty() {
+            return Err(PieceParseError { row: row_index, block: 0, text: String::new(), kind: PieceParseErrorKind::EmptyRow });
+        }
+
+        let mut tiles: Vec<TileId> = vec!();
+        for (block_index, block) in row.split('_').enumerate() {
+            let err = |kind: PieceParseErrorKind, text: &str| PieceParseError {
+                row: row_index,
+                block: block_index,
+                text: String::from(text),
+                kind: kind
+            };
+            let mut comp = block.trim().splitn(2, ':');
+
+            let tile_str = comp.next().unwrap_or("");
+            let count_str = match comp.next() {
+                Some(c) => c,
+                None => { return Err(err(PieceParseErrorKind::MissingCount, block)); }
+            };
+            let tile = tile_str.parse::<usize>().map_err(|_| err(PieceParseErrorKind::BadTileId, tile_str))?;
+            let count = count_str.parse::<usize>().map_err(|_| err(PieceParseErrorKind::BadCount, count_str))?;
+            if !registry.contains(TileId(tile)) {
+                return Err(err(PieceParseErrorKind::UnknownTile, tile_str));
+            }
+            for _ in 0..count {
+                tiles.push(TileId(tile));
+            }
+        }
+        data.push(tiles);
+    }
+    Ok(data)
 }
 
 pub fn piece_from_dntp<P: AsRef<path::Path>>(ctx: &mut Context, path: P) -> GameResult<LevelPiece> {
     let mut dntp = String::new();
-    let mut f = filesystem::open(ctx, path)?;
+    let mut f = filesystem::open(ctx, &path)?;
     f.read_to_string(&mut dntp)?;
 
-    piece_from_string(dntp)
+    let piece = piece_from_string(dntp).map_err(|e| {
+        GameError::ResourceLoadError(format!("{} {}", path.as_ref().display(), e))
+    })?;
+    finish_loaded_piece(path, piece)
+}
+
+/// Loads a piece from a .dntp file or a Tiled map (.json, .tmj or .tmx), chosen by extension.
+/// `table` maps the tile ids of Tiled maps.
+pub fn piece_from_file<P: AsRef<path::Path>>(ctx: &mut Context, path: P, table: &tiled::TiledTileTable) -> GameResult<LevelPiece> {
+    let extension = path.as_ref().extension().and_then(|e| e.to_str()).unwrap_or("");
+    if tiled::is_tiled_extension(extension) {
+        let piece = tiled::piece_from_tiled(ctx, &path, table)?;
+        finish_loaded_piece(path, piece)
+    } else {
+        piece_from_dntp(ctx, path)
+    }
+}
+
+/// Where pieces live in the resources directory
+pub const PIECE_DIR: &str = "/piece";
+
+fn is_piece_extension(extension: &str) -> bool {
+    extension == "dntp" || tiled::is_tiled_extension(extension)
+}
+
+/// Lists the piece files in a directory, sorted by name so the pool order (and so generation) is stable
+pub fn list_piece_files<P: AsRef<path::Path>>(ctx: &mut Context, dir: P) -> GameResult<Vec<path::PathBuf>> {
+    let mut paths: Vec<path::PathBuf> = filesystem::read_dir(ctx, dir)?
+        .filter(|p| p.extension().and_then(|e| e.to_str()).is_some_and(is_piece_extension))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Loads every piece in a directory, mapping Tiled maps through `table`. `_spawn-` files become spawn pieces.
+/// Files that fail to load are logged and skipped so one broken piece doesn't stop the game.
+pub fn load_pieces<P: AsRef<path::Path>>(ctx: &mut Context, dir: P, table: &tiled::TiledTileTable) -> GameResult<Vec<LevelPiece>> {
+    let mut pieces: Vec<LevelPiece> = vec!();
+    for path in list_piece_files(ctx, dir)? {
+        match piece_from_file(ctx, &path, table) {
+            Ok(piece) => pieces.push(piece),
+            Err(e) => println!("Skipping piece {}: {}", path.display(), e)
+        }
+    }
+    Ok(pieces)
+}
+
+/// Applies filename conventions to a freshly parsed piece and rejects it if it fails validation
+fn finish_loaded_piece<P: AsRef<path::Path>>(path: P, mut piece: LevelPiece) -> GameResult<LevelPiece> {
+    apply_filename_conventions(&path, &mut piece);
+    let report = validate_piece(&piece, PLAYER_JUMP_HEIGHT);
+    if !report.is_valid() {
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        return Err(GameError::ResourceLoadError(format!("{} is not a valid piece: {}", path.as_ref().display(), problems.join("; "))));
+    }
+    Ok(piece)
+}
+
+pub const SPAWN_PREFIX: &str = "_spawn-";
+
+/// Applies what a piece's filename says about it. v1 pieces mark spawn rooms with `SPAWN_PREFIX`.
+pub fn apply_filename_conventions<P: AsRef<path::Path>>(path: P, piece: &mut LevelPiece) {
+    let is_spawn_file = path.as_ref().file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(SPAWN_PREFIX));
+    piece.meta.spawn |= is_spawn_file;
+}
+
+/// How many tiles the player can rise in a single jump.
+/// A held jump moves 30/8 px per frame for 40 frames, then decelerates by 0.7 per frame
+/// for roughly 4.8 tiles in total; the last partial tile is not counted.
+pub const PLAYER_JUMP_HEIGHT: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PieceProblem {
+    Empty,
+    RaggedRow { row: usize, width: usize, expected: usize },
+    WrongWidth { width: usize },
+    MarkerOutOfBounds(PieceMarker),
+    NoTopOpening,
+    NoBottomOpening,
+    Untraversable,
+    ExitUnreachable
+}
+
+impl fmt::Display for PieceProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PieceProblem::Empty => write!(f, "piece has no rows"),
+            PieceProblem::RaggedRow { row, width, expected } => write!(f, "row {} is {} tiles wide, expected {}", row, width, expected),
+            PieceProblem::WrongWidth { width } => write!(f, "piece is {} tiles wide, expected {}", width, LEVEL_WIDTH),
+            PieceProblem::MarkerOutOfBounds(m) => write!(f, "{} marker at ({}, {}) is outside the piece", m.marker_type.name(), m.x, m.y),
+            PieceProblem::NoTopOpening => write!(f, "top row has no opening"),
+            PieceProblem::NoBottomOpening => write!(f, "bottom row has no opening"),
+            PieceProblem::Untraversable => write!(f, "bottom opening cannot be reached from the top"),
+            PieceProblem::ExitUnreachable => write!(f, "exit marker cannot be reached from the top")
+        }
+    }
+}
+
+/// The result of `validate_piece`
+#[derive(Clone, Debug)]
+pub struct PieceReport {
+    pub problems: Vec<PieceProblem>,
+    pub traversable: bool
+}
+
+impl PieceReport {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks that a piece is rectangular, `LEVEL_WIDTH` wide, and that the player
+/// can get from the top opening (or a spawn marker) to the bottom opening.
+/// Floor exit pieces (those with an exit marker) must reach an exit instead,
+/// and may have a closed bottom.
+pub fn validate_piece(piece: &LevelPiece, jump_height: usize) -> PieceReport {
+    let mut problems: Vec<PieceProblem> = vec!();
+    let data = &piece.data;
+    if data.is_empty() {
+        return PieceReport { problems: vec!(PieceProblem::Empty), traversable: false };
+    }
+
+    let width = data[0].len();
+    for (i, row) in data.iter().enumerate() {
+        if row.len() != width {
+            problems.push(PieceProblem::RaggedRow { row: i, width: row.len(), expected: width });
+        }
+    }
+    if width != LEVEL_WIDTH as usize {
+        problems.push(PieceProblem::WrongWidth { width: width });
+    }
+    for marker in &piece.markers {
+        if marker.y >= data.len() || marker.x >= data[marker.y].len() {
+            problems.push(PieceProblem::MarkerOutOfBounds(*marker));
+        }
+    }
+    // Reachability is meaningless on a ragged grid
+    if problems.iter().any(|p| matches!(p, PieceProblem::RaggedRow { .. })) {
+        return PieceReport { problems: problems, traversable: false };
+    }
+
+    let registry = tiles::registry();
+    let open = |x: usize, y: usize| !registry.collides(data[y][x]);
+    let top_open = (0..width).any(|x| open(x, 0));
+    let bottom_open = (0..width).any(|x| open(x, data.len() - 1));
+    let is_exit = piece.is_exit();
+    if !top_open { problems.push(PieceProblem::NoTopOpening); }
+    if !bottom_open && !is_exit { problems.push(PieceProblem::NoBottomOpening); }
+
+    let mut starts: Vec<(usize, usize)> = (0..width).filter(|&x| open(x, 0)).map(|x| (x, 0)).collect();
+    for marker in piece.markers.iter().filter(|m| m.marker_type == MarkerType::PlayerSpawn) {
+        if marker.y < data.len() && marker.x < width && open(marker.x, marker.y) {
+            starts.push((marker.x, marker.y));
+        }
+    }
+    let goals: Vec<(usize, usize)> = if is_exit {
+        piece.markers.iter()
+            .filter(|m| m.marker_type == MarkerType::Exit && m.y < data.len() && m.x < width)
+            .map(|m| (m.x, m.y))
+            .collect()
+    } else {
+        (0..width).filter(|&x| open(x, data.len() - 1)).map(|x| (x, data.len() - 1)).collect()
+    };
+    let traversable = is_traversable(data, &starts, &goals, jump_height);
+    if is_exit && top_open && !traversable {
+        problems.push(PieceProblem::ExitUnreachable);
+    } else if !is_exit && top_open && bottom_open && !traversable {
+        problems.push(PieceProblem::Untraversable);
+    }
+    PieceReport { problems: problems, traversable: traversable }
+}
+
+/// Flood-fills the open tiles reachable from `starts` and reports whether any of `goals` is reached.
+/// Movement is approximated on the tile grid: walking along the ground, jumping up to
+/// `jump_height` tiles with one tile of air control per tile risen, and drifting
+/// at most one tile sideways per tile fallen. Platforms and ladder tops can be stood on
+/// and dropped through, and ladders can be climbed any distance.
+fn is_traversable(data: &[Vec<TileId>], starts: &[(usize, usize)], goals: &[(usize, usize)], jump_height: usize) -> bool {
+    let height = data.len();
+    let width = data[0].len();
+    let registry = tiles::registry();
+    let collision = |x: usize, y: usize| registry.def(data[y][x]).collision;
+    let open = |x: usize, y: usize| collision(x, y) != Collision::Solid;
+    let ladder = |x: usize, y: usize| collision(x, y) == Collision::Ladder;
+    // Tiles below the piece count as open, since the player falls out of the bottom
+    let grounded = |x: usize, y: usize| ladder(x, y) || (y + 1 < height && collision(x, y + 1) != Collision::None);
+
+    // visited[y][x][j] where j is the remaining rise of the current jump
+    let mut visited = vec![vec![vec![false; jump_height + 1]; width]; height];
+    let mut queue: Vec<(usize, usize, usize)> = vec!();
+    for &(x, y) in starts {
+        queue.push((x, y, 0));
+    }
+
+    while let Some((x, y, j)) = queue.pop() {
+        if visited[y][x][j] { continue; }
+        visited[y][x][j] = true;
+        if goals.contains(&(x, y)) { return true; }
+
+        let mut next: Vec<(usize, usize, usize)> = vec!();
+        if grounded(x, y) {
+            next.push((x, y, jump_height));
+            if x > 0 && open(x - 1, y) { next.push((x - 1, y, 0)); }
+            if x + 1 < width && open(x + 1, y) { next.push((x + 1, y, 0)); }
+            // Dropping through a platform or climbing down a ladder
+            if y + 1 < height && open(x, y + 1) { next.push((x, y + 1, 0)); }
+        }
+        if ladder(x, y) && y > 0 && open(x, y - 1) {
+            next.push((x, y - 1, 0));
+        }
+        if j > 0 {
+            if y > 0 && open(x, y - 1) { next.push((x, y - 1, j - 1)); }
+            if x > 0 && open(x - 1, y) { next.push((x - 1, y, j - 1)); }
+            if x + 1 < width && open(x + 1, y) { next.push((x + 1, y, j - 1)); }
+        }
+        if !grounded(x, y) && y + 1 < height {
+            next.push((x, y + 1, 0));
+            if x > 0 && open(x - 1, y) && open(x - 1, y + 1) { next.push((x - 1, y + 1, 0)); }
+            if x + 1 < width && open(x + 1, y) && open(x + 1, y + 1) { next.push((x + 1, y + 1, 0)); }
+        }
+        for n in next {
+            if !visited[n.1][n.0][n.2] { queue.push(n); }
+        }
+    }
+    false
+}
+
+message_error!(
+    /// An error produced while writing a piece that a .dntp file can't represent
+    PieceWriteError
+);
+
+/// Serializes a piece back into the .dntp run-length format.
+/// A v2 header is only written when the piece has non-default metadata or markers.
+/// Parsing the output with `piece_from_string` yields an identical piece; rows with no tiles and metadata
+/// that wouldn't, like a name with line breaks or surrounding spaces or a biome containing `,`, are an error.
+pub fn piece_to_string(piece: &LevelPiece) -> Result<String, PieceWriteError> {
+    if let Some(row) = piece.data.iter().position(|row| row.is_empty()) {
+        return PieceWriteError::err(format!("row {} has no tiles", row));
+    }
+    let rows: Vec<String> = piece.data.iter().map(|row| {
+        let mut blocks: Vec<String> = vec!();
+        let mut run: Option<(TileId, usize)> = None;
+        for &tile in row {
+            run = match run {
+                Some((t, count)) if t == tile => Some((t, count + 1)),
+                Some((t, count)) => {
+                    blocks.push(format!("{}:{}", t.0, count));
+                    Some((tile, 1))
+                },
+                None => Some((tile, 1))
+            };
+        }
+        if let Some((t, count)) = run {
+            blocks.push(format!("{}:{}", t.0, count));
+        }
+        blocks.join("_")
+    }).collect();
+
+    let meta = &piece.meta;
+    if *meta == PieceMeta::default() && piece.markers.is_empty() {
+        return Ok(rows.join("~\n"));
+    }
+    let mut header = format!("{} {}\n", DNTP_MAGIC, DNTP_VERSION);
+    for (key, value) in meta_values(meta) {
+        // Each value has to read back as itself
+        let mut reread = PieceMeta::default();
+        let same = set_meta_value(&mut reread, key, &value).is_ok()
+            && meta_values(&reread).iter().any(|(k, v)| *k == key && *v == value);
+        if !same {
+            return PieceWriteError::err(format!("'{}' value '{}' can't be written to a .dntp header", key, value));
+        }
+        header += &format!("{}: {}\n", key, value);
+    }
+    for marker in &piece.markers {
+        header += &format!("marker: {} {} {}\n", marker.marker_type.name(), marker.x, marker.y);
+    }
+    Ok(header + DNTP_HEADER_END + "\n" + &rows.join("~\n"))
+}
+
+/// Writes a piece to the given path in the user data directory
+pub fn save_piece<P: AsRef<path::Path>>(ctx: &mut Context, path: P, piece: &LevelPiece) -> GameResult {
+    let mut f = filesystem::create(ctx, path)?;
+    f.write_all(piece_to_string(piece)?.as_bytes())?;
+    Ok(())
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct LevelTile {
-    pub tile_type: TileType,
+    pub tile_type: TileId,
     pub tile_texture: Option<Rect>,
-    pub collide: bool
+    pub collision: Collision,
+    pub light: Option<Light>,
+    pub friction: f32,
+    pub hazard: Option<tiles::Hazard>
 }
 
 pub struct Generator {
     pub pieces: Vec<LevelPiece>,
-    pub colors: [Color; 4]
+    pub colors: [Color; 4],
+    /// The biome of each palette in `colors`, for tileset overrides
+    pub biomes: [&'static str; 4],
+    pub seed: u64
+}
+
+impl Generator {
+    /// Replaces the piece pool with every piece's transformed variants
+    pub fn derive_variants(&mut self) {
+        self.pieces = self.pieces.iter().flat_map(|p| p.variants()).collect();
+    }
+
+    /// Creates the RNG for a floor of this run.
+    /// Each floor gets its own stream, so any floor can be regenerated from the run seed alone.
+    pub fn floor_rng(&self, floor: u32) -> fastrand::Rng {
+        // splitmix64 finalizer, so neighbouring floors get unrelated streams
+        let mut z = self.seed.wrapping_add((floor as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        fastrand::Rng::with_seed(z ^ (z >> 31))
+    }
+
+    /// Picks the palette for a floor. Consecutive floors never share a palette.
+    fn floor_palette(&self, floor: u32) -> usize {
+        let count = self.colors.len();
+        ((self.seed % count as u64) as usize + floor as usize) % count
+    }
+
+    pub fn floor_color(&self, floor: u32) -> Color {
+        self.colors[self.floor_palette(floor)]
+    }
+
+    pub fn floor_biome(&self, floor: u32) -> &'static str {
+        self.biomes[self.floor_palette(floor)]
+    }
+
+    /// Starts a floor from the piece pool: a spawn piece followed by enough pieces to fill `min_height` rows.
+    /// The rest of the floor is streamed in by `extend_level` as the player descends:
+    /// `length` weighted random pieces (or endless when `None`) and then a floor exit piece,
+    /// each chosen so the player can fall into the next one.
+    /// The result depends only on the pool, `seed`, `floor` and `length`.
+    pub fn generate(&self, ctx: &mut Context, floor: u32, length: Option<usize>, min_height: usize) -> GameResult<Level> {
+        let rng = self.floor_rng(floor);
+        let spawns: Vec<&LevelPiece> = self.pieces.iter().filter(|p| p.meta.spawn).collect();
+
+        let mut level = Level::new(self.floor_color(floor));
+        level.biome = Some(String::from(self.floor_biome(floor)));
+        level.push_piece(ctx, &solid_piece(1));
+
+        let spawn = pick_weighted(&rng, &spawns).ok_or_else(|| generator_error("no spawn pieces"))?;
+        level.push_piece(ctx, spawn);
+        level.stream = Some(FloorStream {
+            rng: rng,
+            last_piece: spawn.clone(),
+            pieces_left: length,
+            finished: false
+        });
+
+        self.extend_level(ctx, &mut level, min_height)?;
+        level.init_textures(ctx);
+        Ok(level)
+    }
+
+    /// Appends pieces to a generated level until it is at least `min_height` rows tall or the floor has ended.
+    /// When no piece in the pool lines up below the last one, an open connector (or a plain exit room,
+    /// when the floor should end) is used instead and a warning is logged, so a bad pool can't end a run.
+    pub fn extend_level(&self, ctx: &mut Context, level: &mut Level, min_height: usize) -> GameResult {
+        let mut stream = match level.stream.take() {
+            Some(stream) => stream,
+            None => { return Ok(()); }
+        };
+        let first_new_row = level.height();
+
+        while !stream.finished && level.height() < min_height {
+            let exit_next = stream.pieces_left == Some(0);
+            let candidates: Vec<&LevelPiece> = self.pieces.iter()
+                .filter(|p| !p.meta.spawn && p.is_exit() == exit_next && stream.last_piece.lines_up_with(p))
+                .collect();
+            let piece = match pick_weighted(&stream.rng, &candidates) {
+                Some(piece) => piece.clone(),
+                None => {
+                    let row = level.height() + level.rows_dropped;
+                    if exit_next {
+                        println!("Warning: no floor exit piece fits below row {}, using a plain exit", row);
+                        fallback_exit_piece()
+                    } else {
+                        println!("Warning: no piece fits below row {}, using an open connector", row);
+                        open_piece(CONNECTOR_ROWS)
+                    }
+                }
+            };
+            level.push_piece(ctx, &piece);
+            stream.last_piece = piece;
+
+            if exit_next {
+                level.push_piece(ctx, &solid_piece(1));
+                stream.finished = true;
+            }
+            stream.pieces_left = stream.pieces_left.map(|n| n.saturating_sub(1));
+        }
+
+        level.stream = Some(stream);
+        // Retile the old bottom row too, since it now has neighbours below
+        if level.height() > first_new_row {
+            level.init_texture_rows(ctx, first_new_row.saturating_sub(1), level.height());
+        }
+        Ok(())
+    }
+}
+
+/// Generation state for the part of a floor that has not been streamed in yet
+pub struct FloorStream {
+    rng: fastrand::Rng,
+    last_piece: LevelPiece,
+    pieces_left: Option<usize>,
+    finished: bool
+}
+
+fn generator_error(msg: &str) -> GameError {
+    GameError::ResourceLoadError(format!("Could not generate level: {}", msg))
+}
+
+/// Picks a piece at random, biased by each piece's metadata weight
+fn pick_weighted<'a>(rng: &fastrand::Rng, pieces: &Vec<&'a LevelPiece>) -> Option<&'a LevelPiece> {
+    let total: f32 = pieces.iter().map(|p| p.meta.weight).sum();
+    if pieces.is_empty() || total <= 0.0 { return None; }
+
+    let mut roll = rng.f32() * total;
+    for piece in pieces {
+        if roll < piece.meta.weight { return Some(piece); }
+        roll -= piece.meta.weight;
+    }
+    pieces.last().cloned()
+}
+
+/// A full-width piece of the registry's solid tile, used to cap the top and bottom of a floor
+fn solid_piece(rows: usize) -> LevelPiece {
+    LevelPiece {
+        data: vec![vec![tiles::registry().solid; LEVEL_WIDTH as usize]; rows],
+        meta: PieceMeta::default(),
+        markers: vec!()
+    }
+}
+
+/// Height of the connector used when no piece in the pool fits
+const CONNECTOR_ROWS: usize = 2;
+
+/// A full-width piece of the registry's empty tile. It lines up with anything above and
+/// below that has an opening, so it can join any two pieces.
+fn open_piece(rows: usize) -> LevelPiece {
+    LevelPiece {
+        data: vec![vec![tiles::registry().empty; LEVEL_WIDTH as usize]; rows],
+        meta: PieceMeta::default(),
+        markers: vec!()
+    }
+}
+
+/// An open room with an exit on its floor, used when no exit piece in the pool fits.
+/// The solid cap pushed after every exit piece is its floor.
+fn fallback_exit_piece() -> LevelPiece {
+    let mut piece = open_piece(CONNECTOR_ROWS);
+    piece.markers.push(PieceMarker {
+        marker_type: MarkerType::Exit,
+        x: LEVEL_WIDTH as usize / 2,
+        y: CONNECTOR_ROWS - 1
+    });
+    piece
 }
 
 pub const TILE_DIMS: f32 = 8.0;
 const TILE_ROW_SIZE: f32 = 8.0;
 
 pub fn get_tile_texture_rect(_ctx: &mut Context, region: Rect, index: usize) -> Rect {
+    tile_texture_rect(region, index)
+}
+
+/// Context-free version of `get_tile_texture_rect`, for tools that run without a window
+pub fn tile_texture_rect(region: Rect, index: usize) -> Rect {
     let col: f32 = (index as u32 % TILE_ROW_SIZE as u32) as f32; 
     let row: f32 = (index as u32 / TILE_ROW_SIZE as u32) as f32;
     Rect::new(region.x + (TILE_DIMS + 1.0) * col, region.y + (TILE_DIMS + 1.0) * row, TILE_DIMS, TILE_DIMS)
@@ -248,44 +1343,438 @@ pub fn get_tile_drawn_size(_ctx: &mut Context, scale: f32) -> f32 {
     TILE_DIMS * 6.0 / scale
 }
 
-#[derive(PartialEq, Copy, Clone, Debug)]
-pub enum TileType {
-    Brick = 0,
-    Wood = 1,
-    Metal = 2,
-    Air = 3
+/// The atlas rect of a tile surrounded on all sides by its own type, for previews
+pub fn tile_preview_rect(ctx: &mut Context, tile: TileId) -> Rect {
+    get_tile_texture_rect(ctx, tile_atlas_region(tile), 8)
 }
 
-fn type_to_tile(_ctx: &mut Context, t_type: TileType) -> LevelTile {
-    TILES[t_type as usize]
+/// Picks the autotile variant for a tile from whether its neighbours are the same type,
+/// in the order [up, right, down, left]
+pub fn autotile_index(adjacent: [bool; 4]) -> usize {
+    match adjacent {
+        [true, true, true, true] => { 8 },
+        [true, true, true, false] => { 3 },
+        [true, true, false, true] => { 11 },
+        [true, false, true, true] => { 12 },
+        [false, true, true, true] => { 4 },
+        [true, true, false, false] => { 9 },
+        [true, false, true, false] => { 5 },
+        [false, true, true, false] => { 1 },
+        [true, false, false, true] => { 10 },
+        [false, true, false, true] => { 13 },
+        [false, false, true, true] => { 2 },
+        [true, false, false, false] => { 14 },
+        [false, true, false, false] => { 6 },
+        [false, false, true, false] => { 7 },
+        [false, false, false, true] => { 15 },
+        _ => 0
+    }
 }
 
-const TILES: [LevelTile; 4] = [
-    LevelTile {
-        tile_type: TileType::Brick,
-        tile_texture: None,
-        collide: true
-    },
-    LevelTile {
-        tile_type: TileType::Wood,
-        tile_texture: None,
-        collide: true
-    },
-    LevelTile {
-        tile_type: TileType::Metal,
-        tile_texture: None,
-        collide: true
-    },
+/// The atlas region holding a tile's 16 autotile variants
+pub fn tile_atlas_region(tile: TileId) -> Rect {
+    tiles::registry().def(tile).region
+}
+
+fn type_to_tile(_ctx: &mut Context, registry: &tiles::TileRegistry, tile: TileId) -> LevelTile {
+    level_tile(registry, tile)
+}
+
+/// Context-free version of `type_to_tile`
+fn level_tile(registry: &tiles::TileRegistry, tile: TileId) -> LevelTile {
+    let def = registry.def(tile);
     LevelTile {
-        tile_type: TileType::Air,
+        tile_type: def.id,
         tile_texture: None,
-        collide: false
-    },
-];
-
-const TILE_REGIONS: [Rect; 4] = [
-    Rect::new(0.0, 111.0, 71.0, 17.0),
-    Rect::new(0.0, 111.0, 71.0, 17.0),
-    Rect::new(0.0, 111.0, 71.0, 17.0),
-    Rect::new(0.0, 93.0, 71.0, 17.0)
-];
\ No newline at end of file
+        collision: def.collision,
+        light: def.light,
+        friction: def.friction,
+        hazard: def.hazard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Installs the shipped tile file, which keeps the default tiles' ids and adds platforms and ladders
+    fn use_game_tiles() {
+        tiles::set_registry(tiles::TileRegistry::from_json(include_str!("../resources/tiles.json")).unwrap());
+    }
+
+    /// Builds a piece from rows of tile glyphs
+    fn ascii_piece(rows: &[&str], markers: Vec<PieceMarker>) -> LevelPiece {
+        use_game_tiles();
+        let registry = tiles::registry();
+        LevelPiece {
+            data: rows.iter()
+                .map(|row| row.chars().map(|c| registry.defs().find(|t| t.glyph == c).unwrap().id).collect())
+                .collect(),
+            meta: PieceMeta::default(),
+            markers: markers
+        }
+    }
+
+    fn marker(marker_type: MarkerType, x: usize, y: usize) -> PieceMarker {
+        PieceMarker { marker_type: marker_type, x: x, y: y }
+    }
+
+    fn problems(piece: &LevelPiece) -> Vec<PieceProblem> {
+        validate_piece(piece, PLAYER_JUMP_HEIGHT).problems
+    }
+
+    fn round_trip(piece: &LevelPiece) -> LevelPiece {
+        piece_from_string(piece_to_string(piece).expect("piece should be writable")).expect("written piece should parse")
+    }
+
+    fn assert_same(a: &LevelPiece, b: &LevelPiece) {
+        assert_eq!(a.data, b.data);
+        assert_eq!(a.meta, b.meta);
+        assert_eq!(a.markers, b.markers);
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let dntp = "0:16~\n0:1_3:14_0:1~\n0:1_3:3_1:4_2:4_3:3_0:1";
+        let piece = piece_from_string(String::from(dntp)).unwrap();
+        assert_eq!(piece_to_string(&piece).unwrap(), dntp);
+        assert_same(&piece, &round_trip(&piece));
+    }
+
+    #[test]
+    fn v2_header_round_trip() {
+        let dntp = "#dntp 2\nname: Long Drop\nauthor: someone\ndifficulty: 3\nbiomes: moss, crypt\nweight: 2.5\nspawn: true\nmirror: false\nrepeat: 3\n---\n0:16~\n3:16";
+        let piece = piece_from_string(String::from(dntp)).unwrap();
+        assert_eq!(piece.meta.name.as_deref(), Some("Long Drop"));
+        assert_eq!(piece.meta.biomes, vec!(String::from("moss"), String::from("crypt")));
+        assert_eq!(piece_to_string(&piece).unwrap(), dntp);
+        assert_same(&piece, &round_trip(&piece));
+    }
+
+    #[test]
+    fn markers_round_trip() {
+        let dntp = "#dntp 2\nmarker: spawn 3 0\nmarker: enemy 7 1\nmarker: pickup 8 1\nmarker: exit 15 1\n---\n3:16~\n3:16";
+        let piece = piece_from_string(String::from(dntp)).unwrap();
+        assert_eq!(piece.markers.len(), 4);
+        assert_eq!(piece_to_string(&piece).unwrap(), dntp);
+        assert_same(&piece, &round_trip(&piece));
+    }
+
+    #[test]
+    fn imported_text_is_trimmed() {
+        let mut meta = PieceMeta::default();
+        set_meta_value(&mut meta, "name", "  Long Drop ").unwrap();
+        assert_eq!(meta.name.as_deref(), Some("Long Drop"));
+        assert_eq!(set_meta_value(&mut meta, "author", "one\ntwo"), Err(PieceParseErrorKind::BadHeaderValue));
+    }
+
+    #[test]
+    fn unwritable_meta_is_rejected() {
+        let mut piece = piece_from_string(String::from("3:16")).unwrap();
+        piece.meta.biomes = vec!(String::from("moss,crypt"));
+        assert!(piece_to_string(&piece).is_err());
+        piece.meta.biomes = vec!();
+        piece.meta.name = Some(String::from(" padded "));
+        assert!(piece_to_string(&piece).is_err());
+        piece.meta.name = Some(String::from("two\nlines"));
+        assert!(piece_to_string(&piece).is_err());
+    }
+
+    #[test]
+    fn empty_rows_are_rejected() {
+        let mut piece = piece_from_string(String::from("3:16~\n3:16")).unwrap();
+        piece.data.insert(1, vec!());
+        assert!(piece_to_string(&piece).is_err());
+
+        let err = piece_from_string(String::from("3:16~~3:16")).unwrap_err();
+        assert_eq!((err.kind, err.row), (PieceParseErrorKind::EmptyRow, 1));
+        // Only trailing empty rows are allowed
+        assert_eq!(piece_from_string(String::from("3:16~\n3:16~\n\n")).unwrap().data.len(), 2);
+    }
+
+    #[test]
+    fn open_piece_is_valid() {
+        let piece = ascii_piece(&[
+            "......##########",
+            "................",
+            "##########......"
+        ], vec!());
+        let report = validate_piece(&piece, PLAYER_JUMP_HEIGHT);
+        assert!(report.is_valid());
+        assert!(report.traversable);
+    }
+
+    #[test]
+    fn ragged_rows_skip_reachability() {
+        let piece = ascii_piece(&[
+            "................",
+            "..............."
+        ], vec!());
+        let report = validate_piece(&piece, PLAYER_JUMP_HEIGHT);
+        assert_eq!(report.problems, vec!(
+            PieceProblem::RaggedRow { row: 1, width: 15, expected: 16 }
+        ));
+        assert!(!report.traversable);
+    }
+
+    #[test]
+    fn closed_top_and_bottom_are_reported() {
+        let closed_top = ascii_piece(&[
+            "################",
+            "................"
+        ], vec!());
+        assert_eq!(problems(&closed_top), vec!(PieceProblem::NoTopOpening));
+
+        let closed_bottom = ascii_piece(&[
+            "................",
+            "################"
+        ], vec!());
+        assert_eq!(problems(&closed_bottom), vec!(PieceProblem::NoBottomOpening));
+    }
+
+    #[test]
+    fn solid_middle_row_is_untraversable() {
+        let piece = ascii_piece(&[
+            "................",
+            "################",
+            "................"
+        ], vec!());
+        assert_eq!(problems(&piece), vec!(PieceProblem::Untraversable));
+    }
+
+    #[test]
+    fn platform_row_can_be_dropped_through() {
+        let piece = ascii_piece(&[
+            "................",
+            "----------------",
+            "................"
+        ], vec!());
+        assert!(validate_piece(&piece, PLAYER_JUMP_HEIGHT).traversable);
+        assert!(problems(&piece).is_empty());
+    }
+
+    #[test]
+    fn split_wall_is_untraversable() {
+        let piece = ascii_piece(&[
+            "..##############",
+            ".......#........",
+            ".......#........",
+            ".......#........",
+            "##############.."
+        ], vec!());
+        assert_eq!(problems(&piece), vec!(PieceProblem::Untraversable));
+    }
+
+    #[test]
+    fn jump_height_limits_climbing() {
+        let rows = [
+            ".###############",
+            ".###############",
+            "...............#",
+            "..#............#",
+            "..#............#",
+            "..#............#",
+            "..#............#",
+            "##############.#"
+        ];
+        // Standing on the wall means rising four tiles and moving one across
+        let piece = ascii_piece(&rows, vec!());
+        assert!(!validate_piece(&piece, 4).traversable);
+        assert!(validate_piece(&piece, 5).traversable);
+
+        // A ladder can be climbed any distance
+        let laddered = ascii_piece(&[
+            ".###############",
+            ".###############",
+            "...............#",
+            ".H#............#",
+            ".H#............#",
+            ".H#............#",
+            ".H#............#",
+            "##############.#"
+        ], vec!());
+        assert!(validate_piece(&laddered, 1).traversable);
+    }
+
+    #[test]
+    fn exit_must_be_reachable() {
+        let rows = [
+            "..##############",
+            "................",
+            "################",
+            "#.##############",
+            "################"
+        ];
+        assert_eq!(problems(&ascii_piece(&rows, vec!(marker(MarkerType::Exit, 1, 3)))), vec!(PieceProblem::ExitUnreachable));
+        // Exit pieces may have a closed bottom
+        assert!(problems(&ascii_piece(&rows, vec!(marker(MarkerType::Exit, 12, 1)))).is_empty());
+    }
+
+    #[test]
+    fn fallback_pieces_fit_anywhere() {
+        let narrow = ascii_piece(&[
+            "#.##############",
+            "##############.#"
+        ], vec!());
+        let connector = open_piece(CONNECTOR_ROWS);
+        assert!(narrow.lines_up_with(&connector) && connector.lines_up_with(&narrow));
+        assert!(validate_piece(&connector, PLAYER_JUMP_HEIGHT).is_valid());
+
+        let exit = fallback_exit_piece();
+        assert!(exit.is_exit() && narrow.lines_up_with(&exit));
+        assert!(validate_piece(&exit, PLAYER_JUMP_HEIGHT).is_valid());
+    }
+
+    #[test]
+    fn mirroring_flips_columns_and_markers() {
+        let piece = ascii_piece(&[
+            "...#############",
+            "#############-.."
+        ], vec!(marker(MarkerType::PlayerSpawn, 0, 0), marker(MarkerType::Pickup, 13, 1)));
+        let mirrored = piece.mirrored();
+        assert_eq!(mirrored.data, ascii_piece(&[
+            "#############...",
+            "..-#############"
+        ], vec!()).data);
+        assert_eq!(mirrored.markers, vec!(marker(MarkerType::PlayerSpawn, 15, 0), marker(MarkerType::Pickup, 2, 1)));
+        assert_same(&mirrored.mirrored(), &piece);
+    }
+
+    #[test]
+    fn repeating_stacks_rows_and_markers() {
+        let piece = ascii_piece(&[
+            "..##############",
+            "................"
+        ], vec!(marker(MarkerType::Enemy, 5, 1)));
+        let stacked = piece.repeated(3).unwrap();
+        assert_eq!(stacked.data.len(), 6);
+        for copy in stacked.data.chunks(2) {
+            assert_eq!(copy, &piece.data[..]);
+        }
+        assert_eq!(stacked.markers, vec!(marker(MarkerType::Enemy, 5, 1), marker(MarkerType::Enemy, 5, 3), marker(MarkerType::Enemy, 5, 5)));
+    }
+
+    #[test]
+    fn pieces_that_cant_fall_into_themselves_are_not_repeated() {
+        let piece = ascii_piece(&[
+            "..##############",
+            "##############.."
+        ], vec!());
+        assert!(piece.repeated(0).is_none());
+        assert!(piece.repeated(2).is_none());
+        assert_same(&piece.repeated(1).unwrap(), &piece);
+    }
+
+    fn generator(pieces: Vec<LevelPiece>) -> Generator {
+        Generator {
+            pieces: pieces,
+            colors: [graphics::WHITE; 4],
+            biomes: ["stone", "moss", "crypt", "ember"],
+            seed: 0
+        }
+    }
+
+    #[test]
+    fn derived_variants_split_the_weight() {
+        let mut piece = ascii_piece(&[
+            "...#############",
+            "................"
+        ], vec!());
+        piece.meta.weight = 3.0;
+        piece.meta.repeat = 3;
+        let mut generator = generator(vec!(piece.clone()));
+        generator.derive_variants();
+
+        // The piece and its mirror, each on its own and stacked two and three high
+        let heights: Vec<usize> = generator.pieces.iter().map(|p| p.data.len()).collect();
+        assert_eq!(heights, vec!(2, 2, 4, 4, 6, 6));
+        assert_eq!(generator.pieces[0].data, piece.data);
+        assert_eq!(generator.pieces[1].data, piece.mirrored().data);
+        for variant in &generator.pieces {
+            assert_eq!((variant.meta.weight, variant.meta.mirror, variant.meta.repeat), (0.5, false, 1));
+        }
+    }
+
+    #[test]
+    fn invalid_variants_are_not_derived() {
+        // Symmetrical, so mirroring changes nothing, and it can't fall into a copy of itself
+        let mut closed = ascii_piece(&[
+            "#######..#######",
+            "##.##########.##"
+        ], vec!());
+        closed.meta.repeat = 3;
+        // Stacking a spawn piece would give it two spawns
+        let mut spawn = ascii_piece(&[
+            "...#############",
+            "................"
+        ], vec!(marker(MarkerType::PlayerSpawn, 0, 0)));
+        spawn.meta.spawn = true;
+        spawn.meta.repeat = 3;
+        let mut generator = generator(vec!(closed.clone(), spawn));
+        generator.derive_variants();
+
+        let heights: Vec<usize> = generator.pieces.iter().map(|p| p.data.len()).collect();
+        assert_eq!(heights, vec!(2, 2, 2));
+        assert_eq!(generator.pieces[0].data, closed.data);
+        assert!(generator.pieces[1..].iter().all(|p| p.meta.spawn && p.markers.len() == 1));
+    }
+
+    #[test]
+    fn row_ranges_shift_and_merge() {
+        assert_eq!(shift_rows((10, 20), 5), Some((5, 15)));
+        assert_eq!(shift_rows((2, 20), 5), Some((0, 15)));
+        assert_eq!(shift_rows((0, 4), 5), None);
+        assert_eq!(merge_rows(vec!((10, 12), (0, 3), (4, 6), (11, 20), (30, 30))), vec!((0, 6), (10, 20), (30, 30)));
+        assert_eq!(merge_rows(vec!()), vec!());
+    }
+
+    /// The per-row grids all have a row for every tile row, and markers and lights stay in the rows they were placed in
+    fn assert_aligned(level: &Level, depths: &[usize]) {
+        assert_eq!(level.lightmap.len(), level.height());
+        assert_eq!(level.explored.len(), level.height());
+        assert!(level.lightmap.iter().all(|row| row.len() == level.width()));
+        assert!(level.explored.iter().all(|row| row.len() == level.width()));
+        let marker_depths: Vec<usize> = level.markers.iter().map(|m| m.y + level.rows_dropped).collect();
+        assert_eq!(marker_depths, depths);
+        let light_depths: Vec<usize> = level.lights.iter().map(|l| l.pos.y as usize + level.rows_dropped).collect();
+        assert_eq!(light_depths, depths);
+        assert!(level.markers.iter().all(|m| level.tiles[m.y][m.x].tile_type == TileId(3)));
+    }
+
+    #[test]
+    fn dropping_rows_keeps_streamed_rows_aligned() {
+        let piece = ascii_piece(&[
+            "..##############",
+            "................",
+            "................",
+            "##############.."
+        ], vec!(marker(MarkerType::Exit, 1, 2)));
+        let mut level = Level::new(graphics::WHITE);
+        level.append_piece(&solid_piece(1));
+        level.append_piece(&piece);
+        level.append_piece(&piece);
+        assert_aligned(&level, &[3, 7]);
+
+        level.lit_rows = Some((2, 7));
+        assert_eq!(level.remove_rows_above(5), 5);
+        assert_eq!((level.height(), level.rows_dropped), (4, 5));
+        assert_aligned(&level, &[7]);
+        assert_eq!(level.lit_rows, Some((0, 2)));
+        assert!(level.light_dirty.contains(&(0, LIGHT_RADIUS)));
+        assert_eq!(level.light_pos.y, -5.0);
+
+        level.append_piece(&piece);
+        assert_eq!(level.height(), 8);
+        assert_aligned(&level, &[7, 11]);
+        // The new rows and the rows their lights reach need lighting
+        assert_eq!(level.light_dirty.last(), Some(&(0, 7)));
+
+        level.lit_rows = Some((0, 2));
+        assert_eq!(level.remove_rows_above(6), 6);
+        assert_eq!((level.height(), level.rows_dropped), (2, 11));
+        assert_aligned(&level, &[11]);
+        assert_eq!(level.lit_rows, None);
+        assert_eq!(level.remove_rows_above(0), 0);
+        assert_eq!(level.rows_dropped, 11);
+    }
+}
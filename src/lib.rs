@@ -0,0 +1,73 @@
+#![allow(dead_code)]
+
+//! Level and piece code shared by the game and the headless piece tools
+
+use ggez::Context;
+use ggez::nalgebra::Vector2;
+
+/// Declares an error type holding just a message, with `Display`, `Error`, conversion into a
+/// ggez resource load error, and an `err` constructor for early returns
+macro_rules! message_error {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug, PartialEq)]
+        pub struct $name(pub String);
+
+        impl $name {
+            fn err<T>(msg: String) -> Result<T, $name> {
+                Err($name(msg))
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        impl std::error::Error for $name {}
+
+        impl From<$name> for ggez::GameError {
+            fn from(e: $name) -> ggez::GameError {
+                ggez::GameError::ResourceLoadError(e.0)
+            }
+        }
+    };
+}
+
+pub mod level;
+pub mod tiled;
+pub mod tiles;
+
+/// Clamps input value between min and max
+pub fn clamp<T>(input: T, min: T, max: T) -> T 
+where T: PartialOrd<T> {
+    assert!(max > min);
+    if input < min { 
+        min
+    } else if input > max {
+        max
+    } else {
+        input
+    }
+}
+
+pub struct CameraView {
+    pub scale: f32,
+    pub scroll: Vector2<f32>,
+    pub inertia: f32
+}
+
+impl CameraView {
+    pub fn new() -> Self {
+        CameraView {
+            scale: 1.0,
+            scroll: Vector2::new(0.0, 0.0),
+            inertia: 0.9
+        }
+    }
+
+    pub fn set_inertia(&mut self, _ctx: &mut Context, num: f32) {
+        self.inertia = clamp(num, 0.0, 1.0);
+    }
+}
\ No newline at end of file
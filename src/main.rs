@@ -2,6 +2,7 @@
 
 use std::path;
 use std::env;
+use std::collections::HashMap;
 use std::f32::consts::PI;
 use ggez::{Context, ContextBuilder, GameResult};
 use ggez::event::{self, EventHandler, KeyCode, KeyMods};
@@ -12,10 +13,14 @@ use ggez::timer;
 use ggez::audio;
 use ggez::audio::SoundSource;
 use ggez::input::keyboard;
+use ggez::input::mouse::{self, MouseButton};
 use ggez::conf::WindowMode;
-use fastrand;
 
-mod level;
+mod editor;
+mod watch;
+
+use downtone::{level, tiled, tiles, clamp, CameraView};
+use downtone::tiles::Collision;
 
 pub fn main() -> GameResult {
     let resource_dir = if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
@@ -26,19 +31,39 @@ pub fn main() -> GameResult {
         path::PathBuf::from("./resources")
     };
 
-    let cb = ggez::ContextBuilder::new("gamething", "jeffrey-m").add_resource_path(resource_dir);
+    let cb = ggez::ContextBuilder::new("gamething", "jeffrey-m").add_resource_path(&resource_dir);
     let (ctx, event_loop) = &mut cb.build()?;
     graphics::set_default_filter(ctx, graphics::FilterMode::Nearest);
     graphics::set_window_title(ctx, "Downtone");
     graphics::set_mode(ctx, WindowMode::default().resizable(true))?;
-    let state = &mut MainState::new(ctx)?;
+    let seed = parse_seed_arg().unwrap_or_else(|| fastrand::u64(..));
+    println!("Run seed: {}", seed);
+    let state = &mut MainState::new(ctx, seed, resource_dir.clone())?;
+    if env::args().any(|a| a == "--dev") {
+        println!("Dev mode: watching {} for changes", resource_dir.display());
+        state.watcher = Some(watch::ResourceWatcher::new(&resource_dir));
+    }
     event::run(ctx, event_loop, state)
 }
 
+/// Reads the run seed from `--seed <n>` on the command line
+fn parse_seed_arg() -> Option<u64> {
+    let args: Vec<String> = env::args().collect();
+    let index = args.iter().position(|a| a == "--seed")?;
+    match args.get(index + 1).map(|s| s.parse::<u64>()) {
+        Some(Ok(seed)) => Some(seed),
+        _ => {
+            println!("Ignoring invalid --seed argument, using a random seed");
+            None
+        }
+    }
+}
+
 #[derive(PartialEq)]
 enum GameState {
     Menu(MenuState),
     InGame,
+    Editor,
     HaltScreen
 }
 
@@ -54,7 +79,7 @@ pub struct MainState {
     spritebatch: graphics::spritebatch::SpriteBatch,
     music_source: audio::Source,
     font: graphics::Font,
-    text_common: [Text; 4],
+    text_common: [Text; 6],
     player_stats: GameStats,
     player_pos: Vector2<f32>,
     player_vel: Vector2<f32>,
@@ -63,15 +88,43 @@ pub struct MainState {
     generator: level::Generator,
     level: level::Level,
     screen_size: Vector2<f32>,
-    camera: CameraView
+    camera: CameraView,
+    floor_transition: Option<FloorTransition>,
+    editor: Option<editor::Editor>,
+    /// The resources directory on disk, which the editor saves pieces into
+    resource_dir: path::PathBuf,
+    /// Set in dev mode (`--dev`) to hot reload pieces and the atlas when they change on disk
+    watcher: Option<watch::ResourceWatcher>,
+    /// When each kind of hazard tile last hurt the player, in ms
+    hazard_hits: HashMap<tiles::TileId, f32>,
+    climbing: bool,
+    /// Depth (row + rows dropped) of the platform the player is dropping through
+    drop_through: Option<usize>,
+    options: GameOptions
+}
+
+/// Settings changed from the options menu
+struct GameOptions {
+    /// Blends light across tile corners instead of tinting each tile flat
+    smooth_lighting: bool
+}
+
+/// Sub-tiles per side each tile is split into for smooth lighting
+const SMOOTH_LIGHT_STEPS: usize = 4;
+
+/// A fade to black and back, with the next floor loaded at the darkest point
+struct FloorTransition {
+    start: f32,
+    loaded: bool
 }
 
+const FLOOR_TRANSITION_MS: f32 = 1200.0;
+
 impl MainState {
-    fn new(ctx: &mut Context) -> GameResult<MainState> {
+    fn new(ctx: &mut Context, seed: u64, resource_dir: path::PathBuf) -> GameResult<MainState> {
 
-        let mut atlas: graphics::Image = graphics::Image::new(ctx, "/atlas.png").expect("Could not load texture atlas!");
-        atlas.set_filter(graphics::FilterMode::Nearest);
-        let batch = graphics::spritebatch::SpriteBatch::new(atlas.clone());
+        let atlas = load_atlas(ctx).expect("Could not load texture atlas!");
+        let batch = graphics::spritebatch::SpriteBatch::new(atlas);
 
         let mut music = audio::Source::new(ctx, "/audio/menu_loop.ogg")?;
         music.set_repeat(true);
@@ -79,11 +132,13 @@ impl MainState {
         let font_emulogic =  graphics::Font::new(ctx, "/font/emulogic.ttf").expect("Could not load font!");
 
         graphics::set_default_filter(ctx, graphics::FilterMode::Nearest);
-        let text_common: [_; 4] = [
+        let text_common: [_; 6] = [
             Text::new(TextFragment::new("PRESS ENTER").font(font_emulogic)),
             Text::new(TextFragment::new("a game for the 2020-21 APCSP create task").scale(Scale::uniform(0.75 * DEFAULT_FONT_SCALE))),
             Text::new(TextFragment::new("HP").font(font_emulogic)),
-            Text::new(TextFragment::new("100").font(font_emulogic))
+            Text::new(TextFragment::new("100").font(font_emulogic)),
+            Text::new(TextFragment::new(format!("SEED {}", seed)).font(font_emulogic).scale(Scale::uniform(0.5 * DEFAULT_FONT_SCALE))),
+            Text::new(TextFragment::new("FLOOR 0").font(font_emulogic))
         ];
 
         let stats = GameStats {
@@ -98,32 +153,21 @@ impl MainState {
             accessories: [None; 5]
         };
 
-        let pieceA = level::piece_from_dntp(ctx, "/piece/_spawn-0.dntp").unwrap();
-        let pieceB = level::piece_from_dntp(ctx, "/piece/0.dntp").unwrap();
-        let generator = level::Generator {
-            pieces: vec!(pieceA.clone(), pieceB.clone()),
+        tiles::set_registry(tiles::load_game_registry(ctx)?);
+        let table = tiled::load_game_tile_table(ctx)?;
+        let mut generator = level::Generator {
+            pieces: level::load_pieces(ctx, level::PIECE_DIR, &table)?,
             colors: [
                 Color::from_rgb(77, 83, 102),
                 Color::from_rgb(41, 59, 42), //77,102,83
                 Color::from_rgb(92, 49, 59), //102,77,83
                 Color::from_rgb(99, 40, 40)  //102,83,77
-            ]
-        };
-        let mut level = level::Level {
-            tiles: vec!(),
-            lightmap: vec!(),
-            last_update: 0.0,
-            color: {
-                let colors = generator.colors;
-                let i = fastrand::usize(..colors.len());
-                colors[i]
-            }
+            ],
+            biomes: ["stone", "moss", "crypt", "ember"],
+            seed: seed
         };
-        level.push_piece(ctx, &level::piece_from_string(String::from("0:16")).unwrap());
-        level.push_piece(ctx, &pieceA);
-        level.push_piece(ctx, &pieceB);
-        level.push_piece(ctx, &level::piece_from_string(String::from("0:16")).unwrap());
-        level.init_textures(ctx);
+        generator.derive_variants();
+        let level = generator.generate(ctx, stats.floor, FLOOR_LENGTH, STREAM_ROWS_AHEAD)?;
 
         let drawable_size = graphics::drawable_size(ctx);
 
@@ -135,15 +179,26 @@ impl MainState {
             font: font_emulogic,
             text_common: text_common,
             player_stats: stats,
-            player_pos: Vector2::new(level::TILE_DIMS * 6.0 * 8.0, 150.0),
+            player_pos: Vector2::new(0.0, 0.0),
             player_vel: Vector2::new(0.0, 0.0),
             player_facing: Facing::Right,
             player_jump_time: 40,
             generator: generator,
             level: level,
             screen_size: Vector2::new(drawable_size.0, drawable_size.1),
-            camera: CameraView::new()
+            camera: CameraView::new(),
+            floor_transition: None,
+            editor: None,
+            resource_dir: resource_dir,
+            watcher: None,
+            hazard_hits: HashMap::new(),
+            climbing: false,
+            drop_through: None,
+            options: GameOptions {
+                smooth_lighting: false
+            }
         };
+        state.respawn_player(ctx);
         state.level.update_lightmap(ctx, &state.camera, state.screen_size, state.player_pos);
 
         Ok(state)
@@ -166,47 +221,419 @@ impl MainState {
     }
 
     fn is_player_colliding(&self, ctx: &mut Context, dir: Direction) -> bool {
+        self.colliding_tile(ctx, dir).is_some()
+    }
+
+    /// The solid tile the player is pressed against in a direction, preferring hazards
+    fn colliding_tile(&self, ctx: &mut Context, dir: Direction) -> Option<level::LevelTile> {
         assert!(self.is_in_game(ctx), "Tried to check player state while not in game!");
         let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
 
+        // Signed, so tiles past the edges of the level can be tested; those count as solid
         let test_x = match dir {
-            Direction::Left => lvl_pos.x as usize-1,
-            Direction::Right => lvl_pos.x as usize+1,
-            _ => lvl_pos.x as usize
+            Direction::Left => lvl_pos.x as isize-1,
+            Direction::Right => lvl_pos.x as isize+1,
+            _ => lvl_pos.x as isize
         };
         let test_y = match dir {
-            Direction::Up => lvl_pos.y as usize-1,
-            Direction::Down => lvl_pos.y as usize+1,
-            _ => lvl_pos.y as usize
+            Direction::Up => lvl_pos.y as isize-1,
+            Direction::Down => lvl_pos.y as isize+1,
+            _ => lvl_pos.y as isize
         };
-        let test_tile = self.level.get_tile(ctx, test_x, test_y);
-        
         let x_offset = lvl_pos.x % 1.0;
         let y_offset = lvl_pos.y % 1.0;
 
-        let test_tile_b: Option<level::LevelTile> = match dir {
+        // A second tile to test when the player overlaps two
+        let test_pos_b: Option<(isize, isize)> = match dir {
             Direction::Up | Direction::Down => match x_offset {
-                z if z > 0.70 => self.level.get_tile(ctx, test_x+1, test_y),
-                z if z < 0.30 => self.level.get_tile(ctx, test_x-1, test_y),
+                z if z > 0.70 => Some((test_x+1, test_y)),
+                z if z < 0.30 => Some((test_x-1, test_y)),
                 _ => None
             },
             Direction::Left | Direction::Right => match y_offset {
-                z if z > 0.58 => self.level.get_tile(ctx, test_x, test_y+1),
-                z if z < 0.42 => self.level.get_tile(ctx, test_x, test_y-1),
+                z if z > 0.58 => Some((test_x, test_y+1)),
+                z if z < 0.42 => Some((test_x, test_y-1)),
                 _ => None
             }
         };
-        (match (test_tile, test_tile_b) {
-            (Some(tile_a), Some(tile_b)) => tile_a.collide || tile_b.collide,
-            (Some(tile_a), None) => tile_a.collide,
-            (None, Some(tile_b)) => tile_b.collide,
-            _ => false
-        } && match dir {
+        let touching = match dir {
             Direction::Left => x_offset < 0.5,
             Direction::Right => x_offset > 0.5,
             Direction::Up => y_offset < 0.5,
             Direction::Down => y_offset > 0.5
-        })
+        };
+        if !touching { return None; }
+        let mut solid: Vec<level::LevelTile> = vec!();
+        for (x, y) in Some((test_x, test_y)).into_iter().chain(test_pos_b) {
+            let tile = self.level.get_tile_or_wall(ctx, x, y);
+            if self.tile_blocks(ctx, &tile, dir, x, y) {
+                solid.push(tile);
+            }
+        }
+        solid.iter().find(|t| t.hazard.is_some()).or(solid.first()).cloned()
+    }
+
+    /// Whether `tile`, at level position `(x, y)`, stops the player moving in a direction
+    fn tile_blocks(&self, ctx: &mut Context, tile: &level::LevelTile, dir: Direction, x: isize, y: isize) -> bool {
+        let holds_up = || dir == Direction::Down && self.player_vel.y >= 0.0 && !self.climbing
+            && self.drop_through != Some(y.max(0) as usize + self.level.rows_dropped);
+        match tile.collision {
+            Collision::None => false,
+            Collision::Solid => true,
+            // Platforms only hold the player up while falling onto them
+            Collision::Platform => holds_up(),
+            // Same for ladders, but only the top rung, so the player can fall down a ladder shaft
+            Collision::Ladder => holds_up() && self.level.get_tile_or_wall(ctx, x, y - 1).collision != Collision::Ladder
+        }
+    }
+
+    /// The collision of the tile at the player's position, offset by `dy` rows
+    fn player_tile_collision(&self, ctx: &mut Context, dy: usize) -> Collision {
+        let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
+        if lvl_pos.x < 0.0 || lvl_pos.y < 0.0 { return Collision::None; }
+        self.level.get_tile(ctx, lvl_pos.x as usize, lvl_pos.y as usize + dy).map_or(Collision::None, |t| t.collision)
+    }
+
+    /// Starts and stops climbing, and moves the player while on a ladder.
+    /// Returns whether the player is climbing, in which case normal jumping and gravity are skipped.
+    fn update_climbing(&mut self, ctx: &mut Context, grounded: bool) -> bool {
+        let up = keyboard::is_key_pressed(ctx, KeyCode::W);
+        let down = keyboard::is_key_pressed(ctx, KeyCode::S);
+        let on_ladder = self.player_tile_collision(ctx, 0) == Collision::Ladder;
+        let ladder_below = self.player_tile_collision(ctx, 1) == Collision::Ladder;
+
+        if !self.climbing && ((on_ladder && (up || down)) || (ladder_below && grounded && down && !keyboard::is_key_pressed(ctx, KeyCode::Space))) {
+            self.climbing = true;
+        }
+        if self.climbing && (keyboard::is_key_pressed(ctx, KeyCode::Space) || !(on_ladder || ladder_below)) {
+            self.climbing = false;
+        }
+        if !self.climbing { return false; }
+
+        self.player_jump_time = 40;
+        self.player_vel.y = 0.0;
+        if up && !self.is_player_colliding(ctx, Direction::Up) {
+            self.player_vel.y = -CLIMB_SPEED;
+        } else if down && !self.is_player_colliding(ctx, Direction::Down) {
+            self.player_vel.y = CLIMB_SPEED;
+        }
+        true
+    }
+
+    /// Hurts and knocks back the player for each hazard tile they touch or stand in
+    fn check_hazards(&mut self, ctx: &mut Context) -> GameResult {
+        let mut contacts: Vec<(level::LevelTile, Option<Direction>)> = vec!();
+        for &dir in [Direction::Down, Direction::Up, Direction::Left, Direction::Right].iter() {
+            if let Some(tile) = self.colliding_tile(ctx, dir) {
+                contacts.push((tile, Some(dir)));
+            }
+        }
+        // Hazards without collision, like lava, hurt from inside
+        let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
+        if lvl_pos.x >= 0.0 && lvl_pos.y >= 0.0 {
+            if let Some(tile) = self.level.get_tile(ctx, lvl_pos.x as usize, lvl_pos.y as usize) {
+                contacts.push((tile, None));
+            }
+        }
+
+        let time = time_ms(ctx);
+        for (tile, dir) in contacts {
+            let hazard = match tile.hazard {
+                Some(hazard) => hazard,
+                None => continue
+            };
+            if self.hazard_hits.get(&tile.tile_type).is_some_and(|&hit| time - hit < hazard.cooldown) {
+                continue;
+            }
+            self.hazard_hits.insert(tile.tile_type, time);
+            self.modify_player_health(ctx, -hazard.damage);
+            match dir {
+                Some(Direction::Down) | None => { self.player_vel.y = -hazard.knockback; },
+                Some(Direction::Up) => { self.player_vel.y = hazard.knockback; },
+                Some(Direction::Left) => { self.player_vel.x = hazard.knockback; },
+                Some(Direction::Right) => { self.player_vel.x = -hazard.knockback; }
+            }
+            if self.player_stats.health <= 0.0 {
+                return self.kill_player(ctx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Smashes the breakable tile next to the player: below while holding S, above while holding W, otherwise in front.
+    /// Returns whether a tile was broken.
+    fn smash(&mut self, ctx: &mut Context) -> bool {
+        let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
+        if lvl_pos.x < 1.0 || lvl_pos.y < 1.0 { return false; }
+        let (x, y) = (lvl_pos.x as usize, lvl_pos.y as usize);
+        let (x, y) = if keyboard::is_key_pressed(ctx, KeyCode::S) {
+            (x, y + 1)
+        } else if keyboard::is_key_pressed(ctx, KeyCode::W) {
+            (x, y - 1)
+        } else if self.player_facing == Facing::Left {
+            (x - 1, y)
+        } else {
+            (x + 1, y)
+        };
+        let into = self.level.get_tile(ctx, x, y).and_then(|t| tiles::registry().def(t.tile_type).breaks_into);
+        match into {
+            Some(into) => self.level.set_tile(ctx, x, y, into),
+            None => false
+        }
+    }
+
+    /// Restarts the current floor with full health, or ends the play-test in the editor
+    fn kill_player(&mut self, ctx: &mut Context) -> GameResult {
+        let max_health = self.player_stats.max_health;
+        self.modify_player_health(ctx, max_health);
+        self.hazard_hits.clear();
+        if self.editor.as_ref().is_some_and(|e| e.playtesting) {
+            self.stop_playtest(ctx);
+            return Ok(());
+        }
+        self.level = self.generator.generate(ctx, self.player_stats.floor, FLOOR_LENGTH, STREAM_ROWS_AHEAD)?;
+        self.respawn_player(ctx);
+        self.camera.scroll = self.get_camera_scroll(ctx);
+        self.level.update_lightmap(ctx, &self.camera, self.screen_size, self.player_pos);
+        Ok(())
+    }
+
+    /// Friction of the tile the player stands on, or of the tile they're in while airborne
+    fn player_friction(&self, ctx: &mut Context, grounded: bool) -> f32 {
+        let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
+        if lvl_pos.x < 0.0 || lvl_pos.y < 0.0 { return tiles::DEFAULT_FRICTION; }
+        let y = lvl_pos.y as usize + if grounded { 1 } else { 0 };
+        self.level.get_tile(ctx, lvl_pos.x as usize, y).map_or(tiles::DEFAULT_FRICTION, |t| t.friction)
+    }
+
+    /// Moves the player to the level's spawn marker and stops them
+    fn respawn_player(&mut self, ctx: &mut Context) {
+        self.player_pos = match self.level.find_marker(level::MarkerType::PlayerSpawn) {
+            Some(m) => level::lvl_to_screen_coords(ctx, m.x as f32, m.y as f32, self.screen_size.x),
+            None => Vector2::new(level::TILE_DIMS * 6.0 * 8.0, 150.0)
+        };
+        self.reset_player_motion();
+    }
+
+    /// Stops the player and clears any climb, drop-through, jump or hazard cooldown left from before they were moved
+    fn reset_player_motion(&mut self) {
+        self.player_vel = Vector2::new(0.0, 0.0);
+        self.player_jump_time = 40;
+        self.climbing = false;
+        self.drop_through = None;
+        self.hazard_hits.clear();
+    }
+
+    fn is_player_on_exit(&self, ctx: &mut Context) -> bool {
+        let lvl_pos = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
+        lvl_pos.x >= 0.0 && lvl_pos.y >= 0.0 && self.level.has_marker_at(level::MarkerType::Exit, lvl_pos.x as usize, lvl_pos.y as usize)
+    }
+
+    /// Replaces the level with the next floor down and puts the player at its spawn
+    fn next_floor(&mut self, ctx: &mut Context) -> GameResult {
+        self.player_stats.floor += 1;
+        let floor = self.player_stats.floor;
+        self.level = self.generator.generate(ctx, floor, FLOOR_LENGTH, STREAM_ROWS_AHEAD)?;
+        self.respawn_player(ctx);
+        self.camera.scroll = self.get_camera_scroll(ctx);
+        self.level.update_lightmap(ctx, &self.camera, self.screen_size, self.player_pos);
+        self.text_common[5] = Text::new(TextFragment::new(format!("FLOOR {}", floor)).font(self.font));
+        Ok(())
+    }
+
+    /// Opens the piece editor on the first piece in the piece directory
+    fn open_editor(&mut self, ctx: &mut Context) -> GameResult {
+        let paths = editor::Editor::list_pieces(ctx)?;
+        let path = paths.first().cloned().unwrap_or_else(|| path::PathBuf::from("/piece/0.dntp"));
+        let editor = editor::Editor::open(ctx, path)?;
+        self.set_editor(ctx, editor);
+        self.music_source.stop();
+        Ok(())
+    }
+
+    fn set_editor(&mut self, ctx: &mut Context, editor: editor::Editor) {
+        self.level = editor.build_level(ctx, self.generator.floor_color(self.player_stats.floor));
+        self.editor = Some(editor);
+        self.camera.scroll = Vector2::new(0.0, 0.0);
+        self.state = GameState::Editor;
+    }
+
+    /// Rebuilds the level from the edited piece so autotiling reflects the latest changes
+    fn refresh_editor_level(&mut self, ctx: &mut Context) {
+        if let Some(editor) = &self.editor {
+            let color = self.level.color;
+            self.level = editor.build_level(ctx, color);
+        }
+    }
+
+    /// Leaves the editor for the main menu, regenerating the current floor
+    fn close_editor(&mut self, ctx: &mut Context) -> GameResult {
+        self.editor = None;
+        self.level = self.generator.generate(ctx, self.player_stats.floor, FLOOR_LENGTH, STREAM_ROWS_AHEAD)?;
+        self.respawn_player(ctx);
+        self.camera.scroll = Vector2::new(0.0, 0.0);
+        self.state = GameState::Menu(MenuState::Main);
+        Ok(())
+    }
+
+    /// Drops the player into the edited piece at the given tile
+    fn start_playtest(&mut self, ctx: &mut Context, x: usize, y: usize) {
+        if let Some(editor) = &mut self.editor {
+            editor.playtesting = true;
+        }
+        self.refresh_editor_level(ctx);
+        self.player_pos = level::lvl_to_screen_coords(ctx, x as f32, y as f32, self.screen_size.x);
+        self.reset_player_motion();
+        self.state = GameState::InGame;
+    }
+
+    fn stop_playtest(&mut self, ctx: &mut Context) {
+        if let Some(editor) = &mut self.editor {
+            editor.playtesting = false;
+        }
+        self.refresh_editor_level(ctx);
+        self.state = GameState::Editor;
+    }
+
+    fn mouse_tile(&self, ctx: &mut Context) -> Option<(usize, usize)> {
+        let pos = mouse::position(ctx);
+        let height = self.editor.as_ref().map_or(0, |e| e.piece.data.len());
+        editor::mouse_to_tile(pos.x, pos.y, self.camera.scroll.y, self.screen_size.x, height)
+    }
+
+    /// Queues the visible rows of the level into the sprite batch
+    fn queue_level(&mut self, ctx: &mut Context) {
+        let (max_width, max_height) = (self.screen_size.x, self.screen_size.y);
+        // Only rows on screen are drawn, so the cost doesn't grow with the level
+        let tile_size = level::TILE_DIMS * 6.0;
+        let first_row = (self.camera.scroll.y / tile_size).max(1.0) as usize - 1;
+        let last_row = (((self.camera.scroll.y + max_height) / tile_size).max(0.0) as usize + 1).min(self.level.height());
+        for i in first_row..last_row {
+            for n in 0..self.level.width() {
+                let texture = self.level.tiles[i][n].tile_texture.unwrap();
+                let dest = Point2::new(tile_size * (n as f32 + (max_width / tile_size - level::LEVEL_WIDTH) / 2.0), tile_size * i as f32);
+                if !self.options.smooth_lighting {
+                    let light = self.level.lightmap[i][n];
+                    let tile = atlas_drawparam_base(ctx, texture)
+                        .dest(dest)
+                        .scale(Vector2::new(6.0, 6.0))
+                        .color(self.lit_color([light[0] as f32, light[1] as f32, light[2] as f32]));
+                    self.spritebatch.add(tile);
+                    continue;
+                }
+
+                // Each sub-tile is tinted by blending the light of the tile's four corners
+                let corners = [
+                    self.level.corner_light(n, i), self.level.corner_light(n + 1, i),
+                    self.level.corner_light(n, i + 1), self.level.corner_light(n + 1, i + 1)
+                ];
+                let step = level::TILE_DIMS / SMOOTH_LIGHT_STEPS as f32;
+                for sy in 0..SMOOTH_LIGHT_STEPS {
+                    for sx in 0..SMOOTH_LIGHT_STEPS {
+                        let (tx, ty) = ((sx as f32 + 0.5) / SMOOTH_LIGHT_STEPS as f32, (sy as f32 + 0.5) / SMOOTH_LIGHT_STEPS as f32);
+                        let mut light = [0.0; 3];
+                        for c in 0..3 {
+                            let top = corners[0][c] + (corners[1][c] - corners[0][c]) * tx;
+                            let bottom = corners[2][c] + (corners[3][c] - corners[2][c]) * tx;
+                            light[c] = top + (bottom - top) * ty;
+                        }
+                        let (x, y) = (sx as f32 * step, sy as f32 * step);
+                        let tile = atlas_drawparam_base(ctx, Rect::new(texture.x + x, texture.y + y, step, step))
+                            .dest(Point2::new(dest.x + x * 6.0, dest.y + y * 6.0))
+                            .scale(Vector2::new(6.0, 6.0))
+                            .color(self.lit_color(light));
+                        self.spritebatch.add(tile);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The level's colour under the given light, on the lightmap's 0-60 scale
+    fn lit_color(&self, light: [f32; 3]) -> Color {
+        let rgb = self.level.color.to_rgb();
+        let tint = |c: u8, l: f32| (c as f32 * l / level::MAX_LIGHT as f32) as u8;
+        Color::from_rgb(tint(rgb.0, light[0]), tint(rgb.1, light[1]), tint(rgb.2, light[2]))
+    }
+
+    /// Generates rows ahead of the player and drops rows far behind, rebasing positions to match
+    fn stream_level(&mut self, ctx: &mut Context) -> GameResult {
+        let player_row = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x).y.max(0.0) as usize;
+        self.generator.extend_level(ctx, &mut self.level, player_row + STREAM_ROWS_AHEAD)?;
+
+        let dropped = self.level.drop_rows_above(ctx, player_row.saturating_sub(KEEP_ROWS_ABOVE));
+        if dropped > 0 {
+            let shift = dropped as f32 * level::TILE_DIMS * 6.0;
+            self.player_pos.y -= shift;
+            self.camera.scroll.y -= shift;
+        }
+        Ok(())
+    }
+
+    /// Checks the resources directory for changes and reloads what changed
+    fn poll_resources(&mut self, ctx: &mut Context) {
+        let time = time_ms(ctx);
+        let changed = match &mut self.watcher {
+            Some(watcher) if time - watcher.last_poll >= watch::POLL_INTERVAL_MS => {
+                watcher.last_poll = time;
+                watcher.poll()
+            },
+            _ => { return; }
+        };
+        if changed.is_empty() { return; }
+
+        if changed.iter().any(|p| p == path::Path::new("/atlas.png")) {
+            match load_atlas(ctx) {
+                Ok(atlas) => {
+                    self.spritebatch.set_image(atlas);
+                    println!("Reloaded atlas");
+                },
+                Err(e) => println!("Could not reload atlas: {}", e)
+            }
+        }
+        let tiles_changed = changed.iter().any(|p| p == path::Path::new(tiles::TILE_REGISTRY_PATH));
+        if tiles_changed {
+            match tiles::load_registry(ctx, tiles::TILE_REGISTRY_PATH) {
+                Ok(registry) => {
+                    tiles::set_registry(registry);
+                    println!("Reloaded tiles");
+                },
+                Err(e) => println!("Could not reload tiles: {}", e)
+            }
+        }
+        // Piece tile ids are checked against the registry, so new tiles mean reloading pieces too
+        let table_changed = changed.iter().any(|p| p == path::Path::new(tiled::TILE_TABLE_PATH));
+        if tiles_changed || table_changed || changed.iter().any(|p| p.starts_with(level::PIECE_DIR)) {
+            if let Err(e) = self.reload_pieces(ctx) {
+                println!("Could not reload pieces: {}", e);
+            }
+        }
+    }
+
+    /// Re-reads the piece directory and rebuilds the current level around the player
+    fn reload_pieces(&mut self, ctx: &mut Context) -> GameResult {
+        let table = tiled::load_game_tile_table(ctx)?;
+        self.generator.pieces = level::load_pieces(ctx, level::PIECE_DIR, &table)?;
+        self.generator.derive_variants();
+        println!("Reloaded {} pieces", self.generator.pieces.len());
+
+        if let Some(editor) = &mut self.editor {
+            // Unsaved edits win over the file on disk
+            if !editor.dirty {
+                editor.piece = editor::Editor::open(ctx, &editor.path)?.piece;
+            }
+            self.refresh_editor_level(ctx);
+            return Ok(());
+        }
+
+        // Regenerate the floor down to where the player is, then drop the same rows as before so positions still line up
+        let dropped = self.level.rows_dropped;
+        let player_row = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x).y.max(0.0) as usize;
+        let mut level = self.generator.generate(ctx, self.player_stats.floor, FLOOR_LENGTH, dropped + player_row + STREAM_ROWS_AHEAD)?;
+        level.drop_rows_above(ctx, dropped);
+        self.level = level;
+        self.level.update_lightmap(ctx, &self.camera, self.screen_size, self.player_pos);
+        Ok(())
     }
 
     fn get_camera_scroll(&self, ctx: &mut Context) -> Vector2<f32> {
@@ -228,9 +655,17 @@ impl MainState {
 }
 
 const MAX_FALL_SPEED: f32 = 60.0;
+const CLIMB_SPEED: f32 = 16.0;
+/// Number of random pieces between the spawn and exit pieces of a floor, or `None` for an endless floor
+const FLOOR_LENGTH: Option<usize> = Some(12);
+/// How many rows below the player are kept generated
+const STREAM_ROWS_AHEAD: usize = 48;
+/// How many rows above the player are kept before being dropped
+const KEEP_ROWS_ABOVE: usize = 48;
 
 impl EventHandler for MainState {
     fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
+        self.poll_resources(ctx);
         match &self.state {
             GameState::Menu(state) => match state {
                 MenuState::Main => {
@@ -246,12 +681,67 @@ impl EventHandler for MainState {
                 },
                 _ => {}
             },
+            GameState::Editor => {
+                if keyboard::is_key_pressed(ctx, KeyCode::W) {
+                    self.camera.scroll.y = (self.camera.scroll.y - 12.0).max(0.0);
+                }
+                if keyboard::is_key_pressed(ctx, KeyCode::S) && !keyboard::active_mods(ctx).contains(KeyMods::CTRL) {
+                    let max_scroll = (self.level.height() as f32 * level::TILE_DIMS * 6.0 - self.screen_size.y).max(0.0);
+                    self.camera.scroll.y = (self.camera.scroll.y + 12.0).min(max_scroll);
+                }
+
+                if let Some((x, y)) = self.mouse_tile(ctx) {
+                    let tile = if mouse::button_pressed(ctx, MouseButton::Left) {
+                        self.editor.as_ref().map(|e| e.brush)
+                    } else if mouse::button_pressed(ctx, MouseButton::Right) {
+                        Some(tiles::registry().empty)
+                    } else {
+                        None
+                    };
+                    let changed = match (tile, &mut self.editor) {
+                        (Some(tile), Some(editor)) => editor.paint(x, y, tile),
+                        _ => false
+                    };
+                    if changed {
+                        self.refresh_editor_level(ctx);
+                    }
+
+                    // The cursor acts as the light source so lighting can be previewed
+                    let time = time_ms(ctx);
+                    if changed || time - self.level.last_update > 50.0 {
+                        let light_pos = level::lvl_to_screen_coords(ctx, x as f32, y as f32, self.screen_size.x);
+                        self.level.update_lightmap(ctx, &self.camera, self.screen_size, light_pos);
+                        self.level.last_update = time;
+                    }
+                }
+            },
             GameState::InGame => {
+                if let Some(transition) = &mut self.floor_transition {
+                    let time = time_ms(ctx);
+                    let elapsed = time - transition.start;
+                    if !transition.loaded && elapsed >= FLOOR_TRANSITION_MS / 2.0 {
+                        transition.loaded = true;
+                        self.next_floor(ctx)?;
+                    } else if elapsed >= FLOOR_TRANSITION_MS {
+                        self.floor_transition = None;
+                    }
+                    return Ok(());
+                }
+                if self.is_player_on_exit(ctx) && self.editor.as_ref().is_some_and(|e| e.playtesting) {
+                    self.stop_playtest(ctx);
+                    return Ok(());
+                }
+                if self.is_player_on_exit(ctx) {
+                    let time = time_ms(ctx);
+                    self.floor_transition = Some(FloorTransition { start: time, loaded: false });
+                    return Ok(());
+                }
+
                 let player_tile = level::screen_to_lvl_coords(ctx, self.player_pos.x, self.player_pos.y, self.screen_size.x);
                 let grounded = self.is_player_colliding(ctx, Direction::Down);
                 let x_speed_mult = if grounded { 1.0 } else { 1.25 };
 
-                self.player_vel.x *= 0.85;
+                self.player_vel.x *= 1.0 - self.player_friction(ctx, grounded);
                 if keyboard::is_key_pressed(ctx, KeyCode::A) {
                     self.player_vel.x -= self.player_stats.speed * x_speed_mult;
                 }
@@ -271,28 +761,41 @@ impl EventHandler for MainState {
                     }
                 }
 
-                if keyboard::is_key_pressed(ctx, KeyCode::Space) && self.player_jump_time > 0 {
-                    if self.player_jump_time == 40 {
-                        self.player_vel.y -= 30.0;
-                    }
-                    self.player_jump_time -= 1;
+                // Down + jump while standing on a platform or ladder top drops through it
+                let player_depth = player_tile.y.max(0.0) as usize + self.level.rows_dropped;
+                let on_one_way = grounded && self.colliding_tile(ctx, Direction::Down).is_some_and(|t| t.collision != Collision::Solid);
+                if on_one_way && keyboard::is_key_pressed(ctx, KeyCode::S) && keyboard::is_key_pressed(ctx, KeyCode::Space) {
+                    self.drop_through = Some(player_depth + 1);
+                    self.player_jump_time = 0;
+                }
+                if self.drop_through.is_some_and(|depth| player_depth > depth) {
+                    self.drop_through = None;
                 }
 
-                if !grounded { 
-                    if !keyboard::is_key_pressed(ctx, KeyCode::Space) || self.player_jump_time == 0 {
-                        self.player_jump_time = 0;
-                        if self.player_vel.y < MAX_FALL_SPEED {
-                            self.player_vel.y += 0.7; 
-                        } else {
-                            self.player_vel.y = MAX_FALL_SPEED;
+                if !self.update_climbing(ctx, grounded) {
+                    if keyboard::is_key_pressed(ctx, KeyCode::Space) && self.player_jump_time > 0 {
+                        if self.player_jump_time == 40 {
+                            self.player_vel.y -= 30.0;
                         }
+                        self.player_jump_time -= 1;
                     }
-                } else {
-                    if !keyboard::is_key_pressed(ctx, KeyCode::Space) {
-                        self.player_jump_time = 40;
-                    }
-                    if player_tile.y % 1.0 > 0.51 {
-                        self.player_pos.y -= 0.01 * level::TILE_DIMS * 6.0;
+
+                    if !grounded { 
+                        if !keyboard::is_key_pressed(ctx, KeyCode::Space) || self.player_jump_time == 0 {
+                            self.player_jump_time = 0;
+                            if self.player_vel.y < MAX_FALL_SPEED {
+                                self.player_vel.y += 0.7; 
+                            } else {
+                                self.player_vel.y = MAX_FALL_SPEED;
+                            }
+                        }
+                    } else {
+                        if !keyboard::is_key_pressed(ctx, KeyCode::Space) {
+                            self.player_jump_time = 40;
+                        }
+                        if player_tile.y % 1.0 > 0.51 {
+                            self.player_pos.y -= 0.01 * level::TILE_DIMS * 6.0;
+                        }
                     }
                 }
 
@@ -300,9 +803,14 @@ impl EventHandler for MainState {
                     self.player_vel.y = 0.0;
                 }
 
+                self.check_hazards(ctx)?;
+                if !self.is_in_game(ctx) { return Ok(()); }
+
                 self.player_pos.x += self.player_vel.x / 8.0;
                 self.player_pos.y += self.player_vel.y / 8.0;
 
+                self.stream_level(ctx)?;
+
                 //println!("{:?}", player_tile);
             },
             _ => {}
@@ -314,7 +822,7 @@ impl EventHandler for MainState {
         graphics::clear(ctx, graphics::BLACK);
         graphics::set_default_filter(ctx, graphics::FilterMode::Nearest);
         let (max_width, max_height): (f32, f32) = (self.screen_size.x, self.screen_size.y);
-        let time = (timer::duration_to_f64(timer::time_since_start(ctx)) * 1000.0) as f32;
+        let time = time_ms(ctx);
         let text_scalef: f32 = 2.0;
 
         match &self.state {
@@ -346,7 +854,18 @@ impl EventHandler for MainState {
                         graphics::queue_text(ctx, &self.text_common[0], Point2::new(-(text_width as f32) / 2.0, 35.0/* + (2.0 * PI * time / cycle_time / 2.0).sin() * 2.0*/), None);
                     }
                 },
-                _ => {}
+                MenuState::Options => {
+                    let lines = [
+                        String::from("OPTIONS"),
+                        format!("L  SMOOTH LIGHTING  {}", if self.options.smooth_lighting { "ON" } else { "OFF" }),
+                        String::from("ESC  BACK")
+                    ];
+                    for (i, line) in lines.iter().enumerate() {
+                        let text = Text::new(TextFragment::new(line.as_str()).font(self.font));
+                        let text_width = text.width(ctx);
+                        graphics::queue_text(ctx, &text, Point2::new(-(text_width as f32) / 2.0, (i as f32 - 1.0) * 24.0), None);
+                    }
+                }
             },
             GameState::InGame => {
                 self.camera.scroll = self.camera.inertia * self.camera.scroll + (1.0 - self.camera.inertia) * self.get_camera_scroll(ctx);
@@ -356,22 +875,7 @@ impl EventHandler for MainState {
                 }
                 
                 // Level drawing
-                {
-                    for i in 0..self.level.height() {
-                        for n in 0..self.level.width() {
-                            let mut color = self.level.color;
-                            let light = self.level.lightmap[i][n] as f32 / 60.0;
-                            let rgb = color.to_rgb();
-                            color = Color::from_rgb((rgb.0 as f32 * light) as u8, (rgb.1 as f32 * light) as u8, (rgb.2 as f32 * light) as u8);
-
-                            let tile = atlas_drawparam_base(ctx, self.level.tiles[i][n].tile_texture.unwrap())
-                                .dest(Point2::new(level::TILE_DIMS * 6.0 * (n as f32 + (max_width / 6.0 / level::TILE_DIMS - level::LEVEL_WIDTH) / 2.0), level::TILE_DIMS * 6.0 * i as f32))
-                                .scale(Vector2::new(6.0, 6.0))
-                                .color(color);
-                            self.spritebatch.add(tile);
-                        }
-                    }
-                }
+                self.queue_level(ctx);
 
                 // Player drawing
                 {
@@ -414,12 +918,49 @@ impl EventHandler for MainState {
 
                     graphics::queue_text(ctx, &self.text_common[3], Point2::new(184.0 - max_width/2.0/text_scalef, 4.0 + 2.0 - max_height/2.0/text_scalef), Some(Color::from_rgb(0,0,0)));
                     graphics::queue_text(ctx, &self.text_common[3], Point2::new(184.0 - max_width/2.0/text_scalef, 4.0 - max_height/2.0/text_scalef), None);
+
+                    graphics::queue_text(ctx, &self.text_common[4], Point2::new(8.0 - max_width/2.0/text_scalef, 24.0 + 1.0 - max_height/2.0/text_scalef), Some(Color::from_rgb(0,0,0)));
+                    graphics::queue_text(ctx, &self.text_common[4], Point2::new(8.0 - max_width/2.0/text_scalef, 24.0 - max_height/2.0/text_scalef), None);
+                }
+            },
+            GameState::Editor => {
+                self.queue_level(ctx);
+
+                // Preview the brush under the cursor
+                let brush = self.editor.as_ref().map_or(tiles::registry().solid, |e| e.brush);
+                if let Some((x, y)) = self.mouse_tile(ctx) {
+                    let tile_size = level::TILE_DIMS * 6.0;
+                    let brush_rect = level::tile_preview_rect(ctx, brush);
+                    let cursor = atlas_drawparam_base(ctx, brush_rect)
+                        .dest(Point2::new(tile_size * (x as f32 + (max_width / tile_size - level::LEVEL_WIDTH) / 2.0), tile_size * y as f32))
+                        .scale(Vector2::new(6.0, 6.0))
+                        .color(Color::new(1.0, 1.0, 1.0, 0.5));
+                    self.spritebatch.add(cursor);
+                }
+
+                if let Some(editor) = &self.editor {
+                    let status = Text::new(TextFragment::new(editor.status()).scale(Scale::uniform(0.75 * DEFAULT_FONT_SCALE)));
+                    let help = Text::new(TextFragment::new("0-9 [ ] brush  LMB paint  RMB erase  W/S scroll  +/- rows  TAB next  P test  CTRL+S save  ESC quit")
+                        .scale(Scale::uniform(0.75 * DEFAULT_FONT_SCALE)));
+                    graphics::queue_text(ctx, &status, Point2::new(8.0 - max_width/2.0/text_scalef, 4.0 - max_height/2.0/text_scalef), None);
+                    graphics::queue_text(ctx, &help, Point2::new(8.0 - max_width/2.0/text_scalef, max_height/2.0/text_scalef - 16.0), None);
                 }
             },
             _ => {}
         };
 
         graphics::draw(ctx, &self.spritebatch, graphics::DrawParam::new().dest(Point2::new(0.0, -self.camera.scroll.y)))?;
+
+        if let Some(transition) = &self.floor_transition {
+            let progress = clamp((time - transition.start) / (FLOOR_TRANSITION_MS / 2.0), 0.0, 2.0);
+            let alpha = 1.0 - (progress - 1.0).abs();
+            let fade = graphics::Mesh::new_rectangle(ctx, graphics::DrawMode::fill(), Rect::new(0.0, 0.0, max_width, max_height), Color::new(0.0, 0.0, 0.0, alpha))?;
+            graphics::draw(ctx, &fade, graphics::DrawParam::new())?;
+            if transition.loaded {
+                let text_width = self.text_common[5].width(ctx);
+                graphics::queue_text(ctx, &self.text_common[5], Point2::new(-(text_width as f32) / 2.0, 0.0), Some(Color::new(1.0, 1.0, 1.0, alpha)));
+            }
+        }
         graphics::draw_queued_text(ctx, graphics::DrawParam::new()
             .dest(Point2::new(max_width/2.0, max_height/2.0))
             .scale(Vector2::new(text_scalef, text_scalef))
@@ -430,17 +971,128 @@ impl EventHandler for MainState {
         Ok(())
     }
 
-    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, _keymods: KeyMods, _repeat: bool) {
+    fn key_down_event(&mut self, ctx: &mut Context, keycode: KeyCode, keymods: KeyMods, repeat: bool) {
+        match self.state {
+            GameState::Menu(MenuState::Main) if keycode == KeyCode::E => {
+                if let Err(e) = self.open_editor(ctx) {
+                    println!("Could not open editor: {}", e);
+                }
+                return;
+            },
+            GameState::Menu(MenuState::Main) if keycode == KeyCode::O => {
+                self.state = GameState::Menu(MenuState::Options);
+                return;
+            },
+            GameState::Menu(MenuState::Options) => {
+                match keycode {
+                    KeyCode::L => { self.options.smooth_lighting = !self.options.smooth_lighting; },
+                    KeyCode::Escape => { self.state = GameState::Menu(MenuState::Main); },
+                    _ => {}
+                }
+                return;
+            },
+            GameState::InGame if keycode == KeyCode::Escape && self.editor.as_ref().is_some_and(|e| e.playtesting) => {
+                self.stop_playtest(ctx);
+                return;
+            },
+            GameState::Editor => {
+                self.editor_key_down(ctx, keycode, keymods);
+                return;
+            },
+            _ => {}
+        }
         match keycode {
             KeyCode::A => { if self.is_in_game(ctx) { self.player_facing = Facing::Left; } },
             KeyCode::D => { if self.is_in_game(ctx) { self.player_facing = Facing::Right; } },
+            KeyCode::J => { if self.is_in_game(ctx) && !repeat && self.floor_transition.is_none() { self.smash(ctx); } },
             _ => {}
         }
     }
 }
 
+impl MainState {
+    fn editor_key_down(&mut self, ctx: &mut Context, keycode: KeyCode, keymods: KeyMods) {
+        let result: GameResult = match keycode {
+            KeyCode::Key1 | KeyCode::Key2 | KeyCode::Key3 | KeyCode::Key4 | KeyCode::Key5 |
+            KeyCode::Key6 | KeyCode::Key7 | KeyCode::Key8 | KeyCode::Key9 | KeyCode::Key0 => {
+                let index = match keycode {
+                    KeyCode::Key1 => 0,
+                    KeyCode::Key2 => 1,
+                    KeyCode::Key3 => 2,
+                    KeyCode::Key4 => 3,
+                    KeyCode::Key5 => 4,
+                    KeyCode::Key6 => 5,
+                    KeyCode::Key7 => 6,
+                    KeyCode::Key8 => 7,
+                    KeyCode::Key9 => 8,
+                    _ => 9
+                };
+                // Brushes follow registry order, so the first ten tiles can be painted from the keyboard
+                if let (Some(editor), Some(&tile)) = (&mut self.editor, tiles::registry().ids().get(index)) {
+                    editor.brush = tile;
+                }
+                Ok(())
+            },
+            // Cycling reaches the tiles past the first ten
+            KeyCode::LBracket | KeyCode::RBracket => {
+                if let Some(editor) = &mut self.editor {
+                    editor.cycle_brush(if keycode == KeyCode::LBracket { -1 } else { 1 });
+                }
+                Ok(())
+            },
+            KeyCode::S if keymods.contains(KeyMods::CTRL) => {
+                match &mut self.editor {
+                    Some(editor) => editor.save(&self.resource_dir),
+                    None => Ok(())
+                }
+            },
+            KeyCode::Equals | KeyCode::Add => {
+                if let Some(editor) = &mut self.editor { editor.add_row(); }
+                self.refresh_editor_level(ctx);
+                Ok(())
+            },
+            KeyCode::Minus | KeyCode::Subtract => {
+                if let Some(editor) = &mut self.editor { editor.remove_row(); }
+                self.refresh_editor_level(ctx);
+                Ok(())
+            },
+            KeyCode::Tab => {
+                let step = if keymods.contains(KeyMods::SHIFT) { -1 } else { 1 };
+                let next = self.editor.as_ref().map(|editor| editor.open_next(ctx, step));
+                match next {
+                    Some(Ok(editor)) => { self.set_editor(ctx, editor); Ok(()) },
+                    Some(Err(e)) => Err(e),
+                    None => Ok(())
+                }
+            },
+            KeyCode::P => {
+                if let Some((x, y)) = self.mouse_tile(ctx) {
+                    self.start_playtest(ctx, x, y);
+                }
+                Ok(())
+            },
+            KeyCode::Escape => self.close_editor(ctx),
+            _ => Ok(())
+        };
+        if let Err(e) = result {
+            println!("Editor error: {}", e);
+        }
+    }
+}
+
+/// Time since the game started, in the milliseconds used by timers and cooldowns
+fn time_ms(ctx: &mut Context) -> f32 {
+    (timer::duration_to_f64(timer::time_since_start(ctx)) * 1000.0) as f32
+}
+
+fn load_atlas(ctx: &mut Context) -> GameResult<graphics::Image> {
+    let mut atlas = graphics::Image::new(ctx, "/atlas.png")?;
+    atlas.set_filter(graphics::FilterMode::Nearest);
+    Ok(atlas)
+}
+
 const ATLAS_WIDTH: f32 = 128.0;
-const ATLAS_HEIGHT: f32 = 128.0;
+const ATLAS_HEIGHT: f32 = 512.0;
 
 /// Converts a Rect to texture atlas coordinates
 pub fn atlas_rect(_ctx: &mut Context, rect: Rect) -> Rect {
@@ -469,19 +1121,6 @@ fn pick_frame_rect(_ctx: &mut Context, frame_rect: Rect, frames: usize, interval
     rect
 }
 
-/// Clamps input value between min and max
-fn clamp<T>(input: T, min: T, max: T) -> T 
-where T: PartialOrd<T> {
-    assert!(max > min);
-    if input < min { 
-        min
-    } else if input > max {
-        max
-    } else {
-        input
-    }
-}
-
 struct GameStats {
     floor: u32,
     score: i32,
@@ -501,7 +1140,7 @@ enum Facing {
 }
 
 // maybe redundant
-#[derive(PartialEq)]
+#[derive(PartialEq, Copy, Clone)]
 enum Direction {
     Up,
     Down,
@@ -526,23 +1165,3 @@ enum Modifier {
     SpdBoost,
     TonBoost
 }
-
-pub struct CameraView {
-    scale: f32,
-    scroll: Vector2<f32>,
-    inertia: f32
-}
-
-impl CameraView {
-    pub fn new() -> Self {
-        CameraView {
-            scale: 1.0,
-            scroll: Vector2::new(0.0, 0.0),
-            inertia: 0.9
-        }
-    }
-
-    pub fn set_inertia(&mut self, _ctx: &mut Context, num: f32) {
-        self.inertia = clamp(num, 0.0, 1.0);
-    }
-}
\ No newline at end of file
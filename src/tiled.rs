@@ -0,0 +1,624 @@
+use std::path;
+use std::io::Read;
+use std::collections::HashMap;
+use ggez::{Context, GameResult, GameError};
+use ggez::filesystem;
+use serde_json::{Value, json};
+use crate::level::{LevelPiece, PieceMeta, PieceMarker, MarkerType, TILE_DIMS, set_meta_value, meta_values, tile_texture_rect};
+use crate::tiles::{self, TileId};
+
+/// Where the game looks for the tile table used to import Tiled maps
+pub const TILE_TABLE_PATH: &str = "/tiled.table";
+
+/// Tiled stores flip flags in the top bits of each gid
+const GID_FLAG_MASK: u32 = 0x1FFF_FFFF;
+
+const INFINITE_MAP_ERROR: &str = "infinite maps are not supported, turn off 'Infinite' in the map properties";
+
+pub fn is_tiled_extension(extension: &str) -> bool {
+    matches!(extension, "json" | "tmj" | "tmx")
+}
+
+message_error!(
+    /// An error produced while reading a Tiled map or tile table
+    TiledError
+);
+
+/// Maps tile ids from a Tiled tileset to registry tiles.
+/// Ids are local to their tileset, as shown in Tiled's tileset editor.
+#[derive(Clone, Debug)]
+pub struct TiledTileTable {
+    pub tiles: HashMap<u32, TileId>,
+    /// Used for cells with no tile
+    pub empty: TileId
+}
+
+impl Default for TiledTileTable {
+    /// Local id n maps to the registry tile with id n
+    fn default() -> Self {
+        let registry = tiles::registry();
+        TiledTileTable {
+            tiles: registry.ids().into_iter().map(|t| (t.0 as u32, t)).collect(),
+            empty: registry.empty
+        }
+    }
+}
+
+impl TiledTileTable {
+    /// Parses a table of `local_id:tile_id` pairs separated by whitespace or `_`, in the style of .dntp blocks.
+    /// `empty:tile_id` sets the tile used for empty cells.
+    pub fn from_string(string: &str) -> Result<TiledTileTable, TiledError> {
+        let registry = tiles::registry();
+        let mut table = TiledTileTable {
+            tiles: HashMap::new(),
+            empty: registry.empty
+        };
+        for pair in string.split(|c: char| c.is_whitespace() || c == '_').filter(|p| !p.is_empty()) {
+            let mut comp = pair.splitn(2, ':');
+            let key = comp.next().unwrap_or("");
+            let tile = comp.next()
+                .and_then(|t| t.parse::<usize>().ok())
+                .map(TileId)
+                .filter(|&t| registry.contains(t));
+            let tile = match tile {
+                Some(t) => t,
+                None => { return TiledError::err(format!("'{}' does not map to a known tile id", pair)); }
+            };
+            if key == "empty" {
+                table.empty = tile;
+            } else {
+                match key.parse::<u32>() {
+                    Ok(id) => { table.tiles.insert(id, tile); },
+                    Err(_) => { return TiledError::err(format!("'{}' is not a tileset id", key)); }
+                }
+            }
+        }
+        Ok(table)
+    }
+
+    /// The gid to write for a tile, assuming a single tileset with firstgid 1
+    fn gid_for(&self, tile: TileId) -> u32 {
+        if tile == self.empty { return 0; }
+        self.tiles.iter()
+            .filter(|(_, &t)| t == tile)
+            .map(|(&id, _)| id + 1)
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn resolve(&self, gid: u32, first_gids: &[u32]) -> Result<TileId, TiledError> {
+        let gid = gid & GID_FLAG_MASK;
+        if gid == 0 { return Ok(self.empty); }
+
+        let first_gid = first_gids.iter().cloned().filter(|&f| f <= gid).max().unwrap_or(1);
+        let local = gid - first_gid;
+        match self.tiles.get(&local) {
+            Some(&t) => Ok(t),
+            None => TiledError::err(format!("tileset id {} has no tile", local))
+        }
+    }
+}
+
+pub fn load_tile_table<P: AsRef<path::Path>>(ctx: &mut Context, path: P) -> GameResult<TiledTileTable> {
+    let mut string = String::new();
+    let mut f = filesystem::open(ctx, &path)?;
+    f.read_to_string(&mut string)?;
+    TiledTileTable::from_string(&string).map_err(|e| {
+        GameError::ResourceLoadError(format!("{}: {}", path.as_ref().display(), e))
+    })
+}
+
+/// Loads the table at `TILE_TABLE_PATH`, or the default table if there isn't one
+pub fn load_game_tile_table(ctx: &mut Context) -> GameResult<TiledTileTable> {
+    if filesystem::exists(ctx, TILE_TABLE_PATH) {
+        load_tile_table(ctx, TILE_TABLE_PATH)
+    } else {
+        Ok(TiledTileTable::default())
+    }
+}
+
+/// Loads a piece from a Tiled map, picking the JSON or TMX reader by extension
+pub fn piece_from_tiled<P: AsRef<path::Path>>(ctx: &mut Context, path: P, table: &TiledTileTable) -> GameResult<LevelPiece> {
+    let mut string = String::new();
+    let mut f = filesystem::open(ctx, &path)?;
+    f.read_to_string(&mut string)?;
+
+    let extension = path.as_ref().extension().and_then(|e| e.to_str()).unwrap_or("");
+    let piece = if extension == "tmx" {
+        piece_from_tmx(&string, table)
+    } else {
+        piece_from_tiled_json(&string, table)
+    };
+    piece.map_err(|e| GameError::ResourceLoadError(format!("{}: {}", path.as_ref().display(), e)))
+}
+
+/// The parts of a Tiled map shared by both file formats
+struct TiledMap {
+    width: usize,
+    height: usize,
+    tile_width: f32,
+    tile_height: f32,
+    first_gids: Vec<u32>,
+    layers: Vec<Vec<u32>>,
+    objects: Vec<TiledObject>,
+    properties: Vec<(String, String)>
+}
+
+struct TiledObject {
+    class: String,
+    x: f32,
+    y: f32,
+    /// Tile objects are anchored at their bottom-left corner instead of the top-left
+    is_tile: bool
+}
+
+impl TiledMap {
+    /// Flattens the tile layers (later layers drawn on top) and converts objects into markers
+    fn into_piece(self, table: &TiledTileTable) -> Result<LevelPiece, TiledError> {
+        if self.layers.is_empty() {
+            return TiledError::err(String::from("map has no tile layers"));
+        }
+
+        let mut gids = vec![0u32; self.width * self.height];
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.len() != gids.len() {
+                return TiledError::err(format!("tile layer {} has {} tiles, expected {}", i, layer.len(), gids.len()));
+            }
+            for (cell, &gid) in gids.iter_mut().zip(layer.iter()) {
+                if gid & GID_FLAG_MASK != 0 { *cell = gid; }
+            }
+        }
+
+        let mut data: Vec<Vec<TileId>> = vec!();
+        for row in gids.chunks(self.width.max(1)) {
+            let mut tiles: Vec<TileId> = vec!();
+            for &gid in row {
+                tiles.push(table.resolve(gid, &self.first_gids)?);
+            }
+            data.push(tiles);
+        }
+
+        let mut markers: Vec<PieceMarker> = vec!();
+        for object in &self.objects {
+            if object.class.is_empty() { continue; }
+            let marker_type = match MarkerType::from_name(&object.class) {
+                Some(t) => t,
+                None => { return TiledError::err(format!("object type '{}' is not a marker type", object.class)); }
+            };
+            let y = if object.is_tile { object.y - self.tile_height } else { object.y };
+            if object.x < 0.0 || y < 0.0 {
+                return TiledError::err(format!("{} object at ({}, {}) is outside the map", object.class, object.x, object.y));
+            }
+            markers.push(PieceMarker {
+                marker_type: marker_type,
+                x: (object.x / self.tile_width) as usize,
+                y: (y / self.tile_height) as usize
+            });
+        }
+
+        let mut meta = PieceMeta::default();
+        for (key, value) in &self.properties {
+            if set_meta_value(&mut meta, key, value).is_err() {
+                return TiledError::err(format!("invalid map property '{}' = '{}'", key, value));
+            }
+        }
+
+        Ok(LevelPiece {
+            data: data,
+            meta: meta,
+            markers: markers
+        })
+    }
+}
+
+/// Parses a map saved in Tiled's JSON format (.json or .tmj)
+pub fn piece_from_tiled_json(string: &str, table: &TiledTileTable) -> Result<LevelPiece, TiledError> {
+    let root: Value = match serde_json::from_str(string) {
+        Ok(v) => v,
+        Err(e) => { return TiledError::err(format!("invalid JSON: {}", e)); }
+    };
+    let number = |v: &Value, key: &str| -> Result<f64, TiledError> {
+        match v.get(key).and_then(|n| n.as_f64()) {
+            Some(n) => Ok(n),
+            None => TiledError::err(format!("missing number '{}'", key))
+        }
+    };
+
+    let mut map = TiledMap {
+        width: number(&root, "width")? as usize,
+        height: number(&root, "height")? as usize,
+        tile_width: number(&root, "tilewidth")? as f32,
+        tile_height: number(&root, "tileheight")? as f32,
+        first_gids: vec!(),
+        layers: vec!(),
+        objects: vec!(),
+        properties: json_properties(&root)
+    };
+    if let Some(tilesets) = root.get("tilesets").and_then(|t| t.as_array()) {
+        for tileset in tilesets {
+            map.first_gids.push(number(tileset, "firstgid")? as u32);
+        }
+    }
+
+    let empty = vec!();
+    let layers = root.get("layers").and_then(|l| l.as_array()).unwrap_or(&empty);
+    for layer in flatten_json_layers(layers) {
+        match layer.get("type").and_then(|t| t.as_str()) {
+            Some("tilelayer") => {
+                if layer.get("chunks").is_some() {
+                    return TiledError::err(String::from(INFINITE_MAP_ERROR));
+                }
+                let data = match layer.get("data").and_then(|d| d.as_array()) {
+                    Some(d) => d,
+                    None => { return TiledError::err(String::from("tile layer data must be saved as CSV, not base64")); }
+                };
+                let mut gids = vec!();
+                for gid in data {
+                    match gid.as_u64().filter(|&g| g <= u32::MAX as u64) {
+                        Some(g) => gids.push(g as u32),
+                        None => { return TiledError::err(format!("'{}' is not a tile gid", gid)); }
+                    }
+                }
+                map.layers.push(gids);
+            },
+            Some("objectgroup") => {
+                for object in layer.get("objects").and_then(|o| o.as_array()).unwrap_or(&empty) {
+                    // Tiled 1.9 renamed an object's "type" to "class"
+                    let class = object.get("class").or_else(|| object.get("type"))
+                        .and_then(|c| c.as_str())
+                        .unwrap_or("");
+                    map.objects.push(TiledObject {
+                        class: String::from(class),
+                        x: number(object, "x")? as f32,
+                        y: number(object, "y")? as f32,
+                        is_tile: object.get("gid").is_some()
+                    });
+                }
+            },
+            _ => {}
+        }
+    }
+    map.into_piece(table)
+}
+
+/// Collects layers in draw order, descending into group layers
+fn flatten_json_layers(layers: &Vec<Value>) -> Vec<&Value> {
+    let mut flat: Vec<&Value> = vec!();
+    for layer in layers {
+        match layer.get("layers").and_then(|l| l.as_array()) {
+            Some(children) => flat.extend(flatten_json_layers(children)),
+            None => flat.push(layer)
+        }
+    }
+    flat
+}
+
+fn json_properties(v: &Value) -> Vec<(String, String)> {
+    let empty = vec!();
+    v.get("properties").and_then(|p| p.as_array()).unwrap_or(&empty).iter()
+        .filter_map(|p| {
+            let name = p.get("name").and_then(|n| n.as_str())?;
+            let value = match p.get("value")? {
+                Value::String(s) => s.clone(),
+                other => other.to_string()
+            };
+            Some((String::from(name), value))
+        })
+        .collect()
+}
+
+/// Parses a map saved in Tiled's XML format (.tmx). Layer data must use CSV or XML encoding.
+pub fn piece_from_tmx(string: &str, table: &TiledTileTable) -> Result<LevelPiece, TiledError> {
+    let doc = match roxmltree::Document::parse(string) {
+        Ok(d) => d,
+        Err(e) => { return TiledError::err(format!("invalid XML: {}", e)); }
+    };
+    let root = doc.root_element();
+    if root.tag_name().name() != "map" {
+        return TiledError::err(String::from("root element is not <map>"));
+    }
+    let number = |node: roxmltree::Node, key: &str| -> Result<f32, TiledError> {
+        match node.attribute(key).and_then(|n| n.parse::<f32>().ok()) {
+            Some(n) => Ok(n),
+            None => TiledError::err(format!("<{}> is missing number '{}'", node.tag_name().name(), key))
+        }
+    };
+
+    let mut map = TiledMap {
+        width: number(root, "width")? as usize,
+        height: number(root, "height")? as usize,
+        tile_width: number(root, "tilewidth")?,
+        tile_height: number(root, "tileheight")?,
+        first_gids: vec!(),
+        layers: vec!(),
+        objects: vec!(),
+        properties: vec!()
+    };
+    read_tmx_children(root, &mut map)?;
+    map.into_piece(table)
+}
+
+fn read_tmx_children(node: roxmltree::Node, map: &mut TiledMap) -> Result<(), TiledError> {
+    for child in node.children().filter(|c| c.is_element()) {
+        match child.tag_name().name() {
+            "tileset" => {
+                match child.attribute("firstgid").and_then(|f| f.parse::<u32>().ok()) {
+                    Some(first_gid) => map.first_gids.push(first_gid),
+                    None => { return TiledError::err(String::from("<tileset> is missing 'firstgid'")); }
+                }
+            },
+            "layer" => {
+                let data = match child.children().find(|c| c.has_tag_name("data")) {
+                    Some(d) => d,
+                    None => { return TiledError::err(String::from("<layer> has no <data>")); }
+                };
+                if data.children().any(|c| c.has_tag_name("chunk")) {
+                    return TiledError::err(String::from(INFINITE_MAP_ERROR));
+                }
+                let gids: Vec<u32> = match data.attribute("encoding") {
+                    Some("csv") => {
+                        let mut gids = vec!();
+                        for gid in data.text().unwrap_or("").split(',').map(|g| g.trim()).filter(|g| !g.is_empty()) {
+                            match gid.parse::<u32>() {
+                                Ok(g) => gids.push(g),
+                                Err(_) => { return TiledError::err(format!("'{}' is not a tile gid", gid)); }
+                            }
+                        }
+                        gids
+                    },
+                    None => {
+                        let mut gids = vec!();
+                        // Empty cells are written as <tile/> with no gid
+                        for tile in data.children().filter(|c| c.has_tag_name("tile")) {
+                            match tile.attribute("gid") {
+                                Some(gid) => match gid.parse::<u32>() {
+                                    Ok(g) => gids.push(g),
+                                    Err(_) => { return TiledError::err(format!("'{}' is not a tile gid", gid)); }
+                                },
+                                None => gids.push(0)
+                            }
+                        }
+                        gids
+                    },
+                    Some(other) => { return TiledError::err(format!("layer encoding '{}' is not supported, save as CSV", other)); }
+                };
+                map.layers.push(gids);
+            },
+            "objectgroup" => {
+                for object in child.children().filter(|c| c.has_tag_name("object")) {
+                    let class = object.attribute("class").or_else(|| object.attribute("type")).unwrap_or("");
+                    map.objects.push(TiledObject {
+                        class: String::from(class),
+                        x: object.attribute("x").and_then(|x| x.parse::<f32>().ok()).unwrap_or(0.0),
+                        y: object.attribute("y").and_then(|y| y.parse::<f32>().ok()).unwrap_or(0.0),
+                        is_tile: object.attribute("gid").is_some()
+                    });
+                }
+            },
+            "group" => { read_tmx_children(child, map)?; },
+            "properties" => {
+                for property in child.children().filter(|c| c.has_tag_name("property")) {
+                    let name = property.attribute("name").unwrap_or("");
+                    // Multi-line string properties store their value as text
+                    let value = property.attribute("value").or_else(|| property.text()).unwrap_or("");
+                    map.properties.push((String::from(name), String::from(value)));
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Name of the tileset file written maps refer to, expected next to the map.
+/// `tileset_tsx` writes it; `resources/piece/` has one for the default table.
+pub const TILESET_SOURCE: &str = "downtone.tsx";
+
+/// Writes a Tiled tileset in which each id of `table` shows its tile's preview from the atlas.
+/// `atlas_source` is the path to the atlas from the tileset file. Tiles are sub-rectangles of
+/// one image, which needs Tiled 1.9 or newer.
+pub fn tileset_tsx(table: &TiledTileTable, atlas_source: &str) -> String {
+    let registry = tiles::registry();
+    let mut ids: Vec<(&u32, &TileId)> = table.tiles.iter().collect();
+    ids.sort();
+
+    let mut tsx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    tsx += &format!("<tileset version=\"1.9\" name=\"downtone\" tilewidth=\"{}\" tileheight=\"{}\" tilecount=\"{}\" columns=\"0\">\n",
+        TILE_DIMS, TILE_DIMS, ids.len());
+    tsx += " <grid orientation=\"orthogonal\" width=\"1\" height=\"1\"/>\n";
+    for (id, &tile) in ids {
+        let rect = tile_texture_rect(registry.def(tile).region, 8);
+        tsx += &format!(" <tile id=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\">\n  <image source=\"{}\"/>\n </tile>\n",
+            id, rect.x, rect.y, rect.w, rect.h, escape_xml(atlas_source));
+    }
+    tsx += "</tileset>\n";
+    tsx
+}
+
+fn piece_gids(piece: &LevelPiece, table: &TiledTileTable) -> Vec<u32> {
+    piece.data.iter().flat_map(|row| row.iter().map(|&t| table.gid_for(t))).collect()
+}
+
+/// Writes a piece as a Tiled JSON map, with markers as point objects and metadata as map properties
+pub fn piece_to_tiled_json(piece: &LevelPiece, table: &TiledTileTable) -> String {
+    let width = piece.data.first().map_or(0, |r| r.len());
+    let objects: Vec<Value> = piece.markers.iter().enumerate().map(|(i, m)| json!({
+        "id": i + 1,
+        "name": "",
+        "type": m.marker_type.name(),
+        "x": (m.x as f32 + 0.5) * TILE_DIMS,
+        "y": (m.y as f32 + 0.5) * TILE_DIMS,
+        "width": 0,
+        "height": 0,
+        "rotation": 0,
+        "point": true,
+        "visible": true
+    })).collect();
+    let properties: Vec<Value> = meta_values(&piece.meta).into_iter().map(|(key, value)| json!({
+        "name": key,
+        "type": "string",
+        "value": value
+    })).collect();
+
+    let map = json!({
+        "type": "map",
+        "version": "1.2",
+        "orientation": "orthogonal",
+        "renderorder": "right-down",
+        "infinite": false,
+        "width": width,
+        "height": piece.data.len(),
+        "tilewidth": TILE_DIMS,
+        "tileheight": TILE_DIMS,
+        "nextlayerid": 3,
+        "nextobjectid": piece.markers.len() + 1,
+        "tilesets": [{ "firstgid": 1, "source": TILESET_SOURCE }],
+        "layers": [
+            {
+                "id": 1,
+                "name": "tiles",
+                "type": "tilelayer",
+                "width": width,
+                "height": piece.data.len(),
+                "x": 0,
+                "y": 0,
+                "opacity": 1,
+                "visible": true,
+                "data": piece_gids(piece, table)
+            },
+            {
+                "id": 2,
+                "name": "markers",
+                "type": "objectgroup",
+                "draworder": "topdown",
+                "x": 0,
+                "y": 0,
+                "opacity": 1,
+                "visible": true,
+                "objects": objects
+            }
+        ],
+        "properties": properties
+    });
+    serde_json::to_string_pretty(&map).unwrap_or_default()
+}
+
+/// Writes a piece as a Tiled TMX map, with markers as point objects and metadata as map properties
+pub fn piece_to_tmx(piece: &LevelPiece, table: &TiledTileTable) -> String {
+    let width = piece.data.first().map_or(0, |r| r.len());
+    let mut tmx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    tmx += &format!("<map version=\"1.2\" orientation=\"orthogonal\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" infinite=\"0\" nextlayerid=\"3\" nextobjectid=\"{}\">\n",
+        width, piece.data.len(), TILE_DIMS, TILE_DIMS, piece.markers.len() + 1);
+
+    let properties = meta_values(&piece.meta);
+    if !properties.is_empty() {
+        tmx += " <properties>\n";
+        for (key, value) in properties {
+            tmx += &format!("  <property name=\"{}\" value=\"{}\"/>\n", key, escape_xml(&value));
+        }
+        tmx += " </properties>\n";
+    }
+    tmx += &format!(" <tileset firstgid=\"1\" source=\"{}\"/>\n", TILESET_SOURCE);
+
+    tmx += &format!(" <layer id=\"1\" name=\"tiles\" width=\"{}\" height=\"{}\">\n  <data encoding=\"csv\">\n", width, piece.data.len());
+    let gids = piece_gids(piece, table);
+    let rows: Vec<String> = gids.chunks(width.max(1))
+        .map(|row| row.iter().map(|g| g.to_string()).collect::<Vec<String>>().join(","))
+        .collect();
+    tmx += &rows.join(",\n");
+    tmx += "\n</data>\n </layer>\n";
+
+    tmx += " <objectgroup id=\"2\" name=\"markers\">\n";
+    for (i, m) in piece.markers.iter().enumerate() {
+        tmx += &format!("  <object id=\"{}\" type=\"{}\" x=\"{}\" y=\"{}\">\n   <point/>\n  </object>\n",
+            i + 1, m.marker_type.name(), (m.x as f32 + 0.5) * TILE_DIMS, (m.y as f32 + 0.5) * TILE_DIMS);
+    }
+    tmx += " </objectgroup>\n</map>\n";
+    tmx
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local ids 0 and 1 are brick and air, and empty cells are air
+    fn table() -> TiledTileTable {
+        TiledTileTable::from_string("0:0 1:3 empty:3").unwrap()
+    }
+
+    fn json_map(data: &str) -> String {
+        format!(r#"{{"width": 2, "height": 2, "tilewidth": 8, "tileheight": 8,
+            "tilesets": [{{"firstgid": 1, "source": "{}"}}],
+            "layers": [{{"type": "tilelayer", "data": {}}}],
+            "properties": [{{"name": "weight", "type": "float", "value": 2.5}}]}}"#, TILESET_SOURCE, data)
+    }
+
+    fn tmx_map(data: &str) -> String {
+        format!(r#"<map width="2" height="2" tilewidth="8" tileheight="8">
+ <tileset firstgid="1" source="{}"/>
+ <layer id="1" name="tiles" width="2" height="2">
+  {}
+ </layer>
+ <objectgroup id="2" name="markers">
+  <object id="1" type="spawn" x="12" y="4"><point/></object>
+ </objectgroup>
+</map>"#, TILESET_SOURCE, data)
+    }
+
+    #[test]
+    fn csv_tmx_layer() {
+        let piece = piece_from_tmx(&tmx_map(r#"<data encoding="csv">1,0,
+2,1</data>"#), &table()).unwrap();
+        assert_eq!(piece.data, vec!(vec!(TileId(0), TileId(3)), vec!(TileId(3), TileId(0))));
+        assert_eq!(piece.markers, vec!(PieceMarker { marker_type: MarkerType::PlayerSpawn, x: 1, y: 0 }));
+    }
+
+    #[test]
+    fn xml_tmx_layer() {
+        let piece = piece_from_tmx(&tmx_map(r#"<data><tile gid="1"/><tile/><tile/><tile gid="1"/></data>"#), &table()).unwrap();
+        assert_eq!(piece.data, vec!(vec!(TileId(0), TileId(3)), vec!(TileId(3), TileId(0))));
+        assert!(piece_from_tmx(&tmx_map(r#"<data><tile gid="x"/><tile/><tile/><tile/></data>"#), &table()).is_err());
+    }
+
+    #[test]
+    fn json_layer() {
+        let piece = piece_from_tiled_json(&json_map("[1, 0, 2, 1]"), &table()).unwrap();
+        assert_eq!(piece.data, vec!(vec!(TileId(0), TileId(3)), vec!(TileId(3), TileId(0))));
+        assert_eq!(piece.meta.weight, 2.5);
+    }
+
+    #[test]
+    fn unknown_gid_is_rejected() {
+        let err = piece_from_tiled_json(&json_map("[1, 0, 9, 1]"), &table()).unwrap_err();
+        assert_eq!(err, TiledError(String::from("tileset id 8 has no tile")));
+        assert!(piece_from_tmx(&tmx_map(r#"<data encoding="csv">1,0,9,1</data>"#), &table()).is_err());
+    }
+
+    #[test]
+    fn malformed_gid_is_rejected() {
+        assert!(piece_from_tiled_json(&json_map("[1, 0, -1, 1]"), &table()).is_err());
+        assert!(piece_from_tiled_json(&json_map("[1, 0, \"2\", 1]"), &table()).is_err());
+    }
+
+    #[test]
+    fn flipped_gid_keeps_its_tile() {
+        // Flipped horizontally, vertically and diagonally
+        let flipped = 1 | !GID_FLAG_MASK;
+        let piece = piece_from_tiled_json(&json_map(&format!("[{}, 0, 0, 1]", flipped)), &table()).unwrap();
+        assert_eq!(piece.data[0][0], TileId(0));
+        let piece = piece_from_tmx(&tmx_map(&format!(r#"<data encoding="csv">{},0,0,1</data>"#, flipped)), &table()).unwrap();
+        assert_eq!(piece.data[0][0], TileId(0));
+    }
+
+    #[test]
+    fn infinite_maps_are_rejected() {
+        let json = json_map("[]").replace(r#""data": []"#, r#""chunks": [{"x": 0, "y": 0, "width": 2, "height": 2, "data": [1, 0, 0, 1]}]"#);
+        assert_eq!(piece_from_tiled_json(&json, &table()).unwrap_err().0, INFINITE_MAP_ERROR);
+        let tmx = tmx_map(r#"<data encoding="csv"><chunk x="0" y="0" width="2" height="2">1,0,0,1</chunk></data>"#);
+        assert_eq!(piece_from_tmx(&tmx, &table()).unwrap_err().0, INFINITE_MAP_ERROR);
+    }
+}
@@ -0,0 +1,437 @@
+use std::path;
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use ggez::{Context, GameResult, GameError};
+use ggez::graphics::{self, Rect, Color};
+use ggez::filesystem;
+use serde_json::Value;
+
+/// Where the game loads its tile definitions from
+pub const TILE_REGISTRY_PATH: &str = "/tiles.json";
+
+/// Size of the atlas block holding a tile's 16 autotile variants: two rows of eight 8px tiles with 1px gaps
+pub const TILE_REGION_W: f32 = 71.0;
+pub const TILE_REGION_H: f32 = 17.0;
+
+/// Share of horizontal speed lost per frame, when a tile doesn't set its own
+pub const DEFAULT_FRICTION: f32 = 0.15;
+
+/// Ids above this are rejected, since the registry is indexed by id
+const MAX_TILE_ID: usize = 4095;
+
+/// Furthest a light can reach, in tiles. Bounds how far the lightmap has to look for sources.
+pub const MAX_LIGHT_RADIUS: f32 = 8.0;
+
+/// Cooldown of a hazard, in ms, when a tile doesn't set its own
+pub const DEFAULT_HAZARD_COOLDOWN: f32 = 500.0;
+
+/// A tile id as written in .dntp pieces
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Copy, Clone, Debug)]
+pub struct TileId(pub usize);
+
+/// How a tile stops the player
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Collision {
+    None,
+    Solid,
+    /// Can be jumped up through and dropped down through with down + jump
+    Platform,
+    /// Can be climbed, and stood on from above like a platform
+    Ladder
+}
+
+impl Collision {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collision::None => "none",
+            Collision::Solid => "solid",
+            Collision::Platform => "platform",
+            Collision::Ladder => "ladder"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Collision> {
+        match name {
+            "none" => Some(Collision::None),
+            "solid" => Some(Collision::Solid),
+            "platform" => Some(Collision::Platform),
+            "ladder" => Some(Collision::Ladder),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileDef {
+    pub id: TileId,
+    pub name: String,
+    pub collision: Collision,
+    /// The tile's autotile block in the atlas
+    pub region: Rect,
+    /// Blocks used instead of `region` on floors of the named biomes
+    pub biome_regions: Vec<(String, Rect)>,
+    pub light: Option<Light>,
+    pub friction: f32,
+    /// Character used for the tile in ASCII previews
+    pub glyph: char,
+    pub hazard: Option<Hazard>,
+    /// The tile left behind when the player smashes this one, if it can be smashed
+    pub breaks_into: Option<TileId>
+}
+
+/// Coloured light given off by a tile or entity
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Light {
+    pub color: Color,
+    /// Distance in tiles at which the light fades out
+    pub radius: f32
+}
+
+/// Harm done to the player on touching a tile
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Hazard {
+    pub damage: f32,
+    /// Speed the player is pushed away from the tile at
+    pub knockback: f32,
+    /// Time in ms before a tile of the same kind can hurt the player again
+    pub cooldown: f32
+}
+
+#[derive(Clone, Debug)]
+pub struct TileRegistry {
+    tiles: Vec<Option<TileDef>>,
+    /// Fills empty space, e.g. cells with no tile in Tiled maps
+    pub empty: TileId,
+    /// Fills solid space, e.g. the caps at the top and bottom of a floor
+    pub solid: TileId
+}
+
+message_error!(
+    /// An error produced while parsing a tile file
+    TileRegistryError
+);
+
+fn tile_def(id: usize, name: &str, collision: Collision, x: f32, y: f32, glyph: char) -> TileDef {
+    TileDef {
+        id: TileId(id),
+        name: String::from(name),
+        collision: collision,
+        region: Rect::new(x, y, TILE_REGION_W, TILE_REGION_H),
+        biome_regions: vec!(),
+        light: None,
+        friction: DEFAULT_FRICTION,
+        glyph: glyph,
+        hazard: None,
+        breaks_into: None
+    }
+}
+
+impl TileDef {
+    /// The autotile block to draw this tile with in a biome
+    pub fn region_for(&self, biome: Option<&str>) -> Rect {
+        biome.and_then(|b| self.biome_regions.iter().find(|(name, _)| name == b))
+            .map_or(self.region, |(_, region)| *region)
+    }
+}
+
+impl Default for TileRegistry {
+    /// The original four tiles, used when there is no tile file
+    fn default() -> Self {
+        TileRegistry {
+            tiles: vec!(
+                Some(tile_def(0, "brick", Collision::Solid, 0.0, 111.0, '#')),
+                Some(tile_def(1, "wood", Collision::Solid, 0.0, 31.0, '=')),
+                Some(tile_def(2, "metal", Collision::Solid, 0.0, 49.0, '%')),
+                Some(tile_def(3, "air", Collision::None, 0.0, 93.0, '.'))
+            ),
+            empty: TileId(3),
+            solid: TileId(0)
+        }
+    }
+}
+
+impl TileRegistry {
+    /// Parses a tile file:
+    /// `{"empty": "<name>", "solid": "<name>", "tiles": [{"id", "name", "collision", "region": [x, y], "light"?, "friction"?, "glyph"?, "biomes"?, "hazard"?, "breaks_into"?}, ...]}`
+    /// where `light` is `{"radius", "color"?: [r, g, b]}`, `biomes` maps biome names to the `[x, y]` of a replacement block,
+    /// `hazard` is `{"damage", "knockback"?, "cooldown"?}` and `breaks_into` names the tile left after smashing.
+    pub fn from_json(string: &str) -> Result<TileRegistry, TileRegistryError> {
+        let root: Value = match serde_json::from_str(string) {
+            Ok(v) => v,
+            Err(e) => { return TileRegistryError::err(format!("invalid JSON: {}", e)); }
+        };
+        let entries = match root.get("tiles").and_then(|t| t.as_array()) {
+            Some(t) => t,
+            None => { return TileRegistryError::err(String::from("missing 'tiles' array")); }
+        };
+
+        let mut tiles: Vec<Option<TileDef>> = vec!();
+        // Tiles can break into tiles defined after them, so names are resolved once all are parsed
+        let mut breaks: Vec<(TileId, &str)> = vec!();
+        for (index, entry) in entries.iter().enumerate() {
+            let def = parse_tile_def(entry).map_err(|e| TileRegistryError(format!("tile {}: {}", index, e)))?;
+            let id = def.id.0;
+            if tiles.len() <= id {
+                tiles.resize(id + 1, None);
+            }
+            if tiles[id].is_some() {
+                return TileRegistryError::err(format!("tile {}: id {} is already used", index, id));
+            }
+            if tiles.iter().flatten().any(|t| t.name == def.name) {
+                return TileRegistryError::err(format!("tile {}: name '{}' is already used", index, def.name));
+            }
+            if let Some(into) = entry.get("breaks_into") {
+                match into.as_str() {
+                    Some(name) => breaks.push((def.id, name)),
+                    None => { return TileRegistryError::err(format!("tile {}: 'breaks_into' must be a tile name", index)); }
+                }
+            }
+            tiles[id] = Some(def);
+        }
+
+        let mut registry = TileRegistry {
+            tiles: tiles,
+            empty: TileId(0),
+            solid: TileId(0)
+        };
+        for (id, name) in breaks {
+            let into = match registry.by_name(name) {
+                Some(into) => into,
+                None => { return TileRegistryError::err(format!("tile {} breaks into undefined tile '{}'", id.0, name)); }
+            };
+            if let Some(Some(def)) = registry.tiles.get_mut(id.0) {
+                def.breaks_into = Some(into);
+            }
+        }
+        registry.empty = registry.named_field(&root, "empty")?;
+        registry.solid = registry.named_field(&root, "solid")?;
+        if registry.def(registry.empty).collision != Collision::None {
+            return TileRegistryError::err(String::from("the empty tile must have no collision"));
+        }
+        if !registry.collides(registry.solid) {
+            return TileRegistryError::err(String::from("the solid tile must be solid"));
+        }
+        Ok(registry)
+    }
+
+    fn named_field(&self, root: &Value, key: &str) -> Result<TileId, TileRegistryError> {
+        let name = match root.get(key).and_then(|v| v.as_str()) {
+            Some(n) => n,
+            None => { return TileRegistryError::err(format!("missing '{}' tile name", key)); }
+        };
+        match self.by_name(name) {
+            Some(id) => Ok(id),
+            None => TileRegistryError::err(format!("'{}' tile '{}' is not defined", key, name))
+        }
+    }
+
+    pub fn get(&self, id: TileId) -> Option<&TileDef> {
+        self.tiles.get(id.0).and_then(|t| t.as_ref())
+    }
+
+    /// Looks up a tile, falling back to the empty tile for unknown ids
+    pub fn def(&self, id: TileId) -> &TileDef {
+        self.get(id).or_else(|| self.get(self.empty)).expect("Tile registry has no empty tile!")
+    }
+
+    pub fn contains(&self, id: TileId) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Whether a tile blocks the player from every side
+    pub fn collides(&self, id: TileId) -> bool {
+        self.def(id).collision == Collision::Solid
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<TileId> {
+        self.defs().find(|t| t.name == name).map(|t| t.id)
+    }
+
+    /// Every defined tile, in id order
+    pub fn defs(&self) -> impl Iterator<Item = &TileDef> {
+        self.tiles.iter().flatten()
+    }
+
+    pub fn ids(&self) -> Vec<TileId> {
+        self.defs().map(|t| t.id).collect()
+    }
+}
+
+fn parse_tile_def(entry: &Value) -> Result<TileDef, String> {
+    let id = match entry.get("id").and_then(|v| v.as_u64()) {
+        Some(id) if id as usize <= MAX_TILE_ID => id as usize,
+        Some(id) => { return Err(format!("id {} is above the maximum of {}", id, MAX_TILE_ID)); },
+        None => { return Err(String::from("missing or invalid 'id'")); }
+    };
+    let name = match entry.get("name").and_then(|v| v.as_str()) {
+        Some(n) if !n.is_empty() => n,
+        _ => { return Err(String::from("missing 'name'")); }
+    };
+    let collision = entry.get("collision")
+        .and_then(|v| v.as_str())
+        .and_then(Collision::from_name)
+        .ok_or_else(|| String::from("'collision' must be one of none, solid, platform or ladder"))?;
+    let region = parse_region(entry.get("region")).ok_or_else(|| String::from("'region' must be the [x, y] of the tile's atlas block"))?;
+
+    let mut def = tile_def(id, name, collision, region.0, region.1, '?');
+    if let Some(biomes) = entry.get("biomes") {
+        let biomes = biomes.as_object().ok_or_else(|| String::from("'biomes' must map biome names to regions"))?;
+        for (biome, region) in biomes {
+            let (x, y) = parse_region(Some(region)).ok_or_else(|| format!("region for biome '{}' must be [x, y]", biome))?;
+            def.biome_regions.push((biome.clone(), Rect::new(x, y, TILE_REGION_W, TILE_REGION_H)));
+        }
+    }
+    if let Some(light) = entry.get("light") {
+        def.light = Some(parse_light(light)?);
+    }
+    if let Some(friction) = entry.get("friction") {
+        def.friction = match friction.as_f64() {
+            Some(f) if (0.0..=1.0).contains(&f) => f as f32,
+            _ => { return Err(String::from("'friction' must be between 0 and 1")); }
+        };
+    }
+    if let Some(glyph) = entry.get("glyph") {
+        let mut chars = glyph.as_str().unwrap_or("").chars();
+        def.glyph = match (chars.next(), chars.next()) {
+            (Some(c), None) => c,
+            _ => { return Err(String::from("'glyph' must be a single character")); }
+        };
+    }
+    if let Some(hazard) = entry.get("hazard") {
+        def.hazard = Some(parse_hazard(hazard)?);
+    }
+    Ok(def)
+}
+
+fn parse_hazard(value: &Value) -> Result<Hazard, String> {
+    let field = |key: &str, default: Option<f64>| match value.get(key) {
+        Some(v) => v.as_f64().filter(|&n| n >= 0.0).ok_or_else(|| format!("hazard '{}' must be a positive number", key)),
+        None => default.ok_or_else(|| format!("hazard is missing '{}'", key))
+    };
+    Ok(Hazard {
+        damage: field("damage", None)? as f32,
+        knockback: field("knockback", Some(0.0))? as f32,
+        cooldown: field("cooldown", Some(DEFAULT_HAZARD_COOLDOWN as f64))? as f32
+    })
+}
+
+fn parse_light(value: &Value) -> Result<Light, String> {
+    let radius = match value.get("radius").and_then(|r| r.as_f64()) {
+        Some(r) if r > 0.0 && r <= MAX_LIGHT_RADIUS as f64 => r as f32,
+        _ => { return Err(format!("light 'radius' must be above 0 and at most {}", MAX_LIGHT_RADIUS)); }
+    };
+    let color = match value.get("color") {
+        Some(color) => {
+            let rgb: Option<Vec<u8>> = color.as_array().and_then(|c| c.iter()
+                .map(|n| n.as_u64().filter(|&n| n <= 255).map(|n| n as u8))
+                .collect());
+            match rgb.as_deref() {
+                Some(&[r, g, b]) => Color::from_rgb(r, g, b),
+                _ => { return Err(String::from("light 'color' must be [r, g, b] with each between 0 and 255")); }
+            }
+        },
+        None => graphics::WHITE
+    };
+    Ok(Light {
+        color: color,
+        radius: radius
+    })
+}
+
+fn parse_region(value: Option<&Value>) -> Option<(f32, f32)> {
+    let region: Vec<f64> = value?.as_array()?.iter().filter_map(|n| n.as_f64()).collect();
+    match region.as_slice() {
+        &[x, y] => Some((x as f32, y as f32)),
+        _ => None
+    }
+}
+
+static REGISTRY: RwLock<Option<Arc<TileRegistry>>> = RwLock::new(None);
+
+/// The tile registry in use. Pieces are parsed, drawn and collided against it.
+pub fn registry() -> Arc<TileRegistry> {
+    if let Some(registry) = REGISTRY.read().expect("Tile registry lock poisoned!").as_ref() {
+        return registry.clone();
+    }
+    let mut lock = REGISTRY.write().expect("Tile registry lock poisoned!");
+    lock.get_or_insert_with(|| Arc::new(TileRegistry::default())).clone()
+}
+
+/// Replaces the tile registry. Pieces should be reloaded afterwards, since their ids are checked on parse.
+pub fn set_registry(registry: TileRegistry) {
+    *REGISTRY.write().expect("Tile registry lock poisoned!") = Some(Arc::new(registry));
+}
+
+pub fn load_registry<P: AsRef<path::Path>>(ctx: &mut Context, path: P) -> GameResult<TileRegistry> {
+    let mut string = String::new();
+    let mut f = filesystem::open(ctx, &path)?;
+    f.read_to_string(&mut string)?;
+    TileRegistry::from_json(&string).map_err(|e| {
+        GameError::ResourceLoadError(format!("{}: {}", path.as_ref().display(), e))
+    })
+}
+
+/// Loads the tile file at `TILE_REGISTRY_PATH`, or the default registry if there isn't one
+pub fn load_game_registry(ctx: &mut Context) -> GameResult<TileRegistry> {
+    if filesystem::exists(ctx, TILE_REGISTRY_PATH) {
+        load_registry(ctx, TILE_REGISTRY_PATH)
+    } else {
+        Ok(TileRegistry::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tile file with the default tiles' names, plus `extra` tile entries
+    fn tile_file(extra: &str) -> String {
+        format!(r#"{{"empty": "air", "solid": "brick", "tiles": [
+            {{"id": 0, "name": "brick", "collision": "solid", "region": [0, 111]}},
+            {{"id": 3, "name": "air", "collision": "none", "region": [0, 93]}}{}
+        ]}}"#, extra)
+    }
+
+    fn parse_error(extra: &str) -> String {
+        TileRegistry::from_json(&tile_file(extra)).unwrap_err().0
+    }
+
+    #[test]
+    fn shipped_tile_file_parses() {
+        let registry = TileRegistry::from_json(include_str!("../resources/tiles.json")).unwrap();
+        assert_eq!((registry.empty, registry.solid), (TileId(3), TileId(0)));
+        assert_eq!(registry.by_name("cracked wood").map(|t| registry.def(t).breaks_into), Some(Some(TileId(3))));
+    }
+
+    #[test]
+    fn id_range_is_checked() {
+        let registry = TileRegistry::from_json(&tile_file(r#", {"id": 4095, "name": "last", "collision": "none", "region": [0, 0]}"#)).unwrap();
+        assert!(registry.contains(TileId(4095)));
+        assert_eq!(parse_error(r#", {"id": 4096, "name": "past", "collision": "none", "region": [0, 0]}"#),
+            "tile 2: id 4096 is above the maximum of 4095");
+        assert_eq!(parse_error(r#", {"id": -1, "name": "negative", "collision": "none", "region": [0, 0]}"#),
+            "tile 2: missing or invalid 'id'");
+    }
+
+    #[test]
+    fn duplicate_ids_and_names_are_rejected() {
+        assert_eq!(parse_error(r#", {"id": 3, "name": "fog", "collision": "none", "region": [0, 0]}"#),
+            "tile 2: id 3 is already used");
+        assert_eq!(parse_error(r#", {"id": 4, "name": "air", "collision": "none", "region": [0, 0]}"#),
+            "tile 2: name 'air' is already used");
+    }
+
+    #[test]
+    fn unknown_collision_is_rejected() {
+        assert_eq!(parse_error(r#", {"id": 4, "name": "goo", "collision": "sticky", "region": [0, 0]}"#),
+            "tile 2: 'collision' must be one of none, solid, platform or ladder");
+    }
+
+    #[test]
+    fn empty_and_solid_tiles_are_checked() {
+        let swapped = tile_file("").replace(r#""empty": "air", "solid": "brick""#, r#""empty": "brick", "solid": "air""#);
+        assert_eq!(TileRegistry::from_json(&swapped).unwrap_err().0, "the empty tile must have no collision");
+        assert_eq!(parse_error(r#", {"id": 4, "name": "rubble", "collision": "none", "region": [0, 0], "breaks_into": "dust"}"#),
+            "tile 4 breaks into undefined tile 'dust'");
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path;
+use std::time::SystemTime;
+
+/// Polls a directory tree for changed files. Used by dev mode to hot reload resources.
+pub struct ResourceWatcher {
+    root: path::PathBuf,
+    stamps: HashMap<path::PathBuf, SystemTime>,
+    /// Game time in ms of the last poll
+    pub last_poll: f32
+}
+
+/// How often the resources directory is scanned, in ms
+pub const POLL_INTERVAL_MS: f32 = 500.0;
+
+impl ResourceWatcher {
+    pub fn new<P: AsRef<path::Path>>(root: P) -> ResourceWatcher {
+        let mut watcher = ResourceWatcher {
+            root: root.as_ref().to_path_buf(),
+            stamps: HashMap::new(),
+            last_poll: 0.0
+        };
+        watcher.stamps = watcher.scan();
+        watcher
+    }
+
+    /// Returns the files added, changed or removed since the last poll, as resource paths like `/piece/0.dntp`
+    pub fn poll(&mut self) -> Vec<path::PathBuf> {
+        let stamps = self.scan();
+        let mut changed: Vec<path::PathBuf> = stamps.iter()
+            .filter(|(p, t)| self.stamps.get(*p) != Some(t))
+            .map(|(p, _)| p.clone())
+            .collect();
+        changed.extend(self.stamps.keys().filter(|p| !stamps.contains_key(*p)).cloned());
+        changed.sort();
+        self.stamps = stamps;
+        changed
+    }
+
+    fn scan(&self) -> HashMap<path::PathBuf, SystemTime> {
+        let mut stamps = HashMap::new();
+        let mut dirs = vec!(self.root.clone());
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue
+            };
+            for entry in entries.filter_map(|e| e.ok()) {
+                let disk_path = entry.path();
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue
+                };
+                if metadata.is_dir() {
+                    dirs.push(disk_path);
+                } else if let (Ok(modified), Ok(relative)) = (metadata.modified(), disk_path.strip_prefix(&self.root)) {
+                    stamps.insert(path::Path::new("/").join(relative), modified);
+                }
+            }
+        }
+        stamps
+    }
+}